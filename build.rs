@@ -125,14 +125,14 @@ fn version_info() -> Option<(Vec<u8>, Option<Vec<u8>>)> {
 }
 
 fn which(bin: &str) -> Option<Vec<u8>> {
-  handle_output(Command::new("sh").args(&["-c", &format!("command -v {}", bin)]), |bytes| {
+  handle_output(Command::new("sh").args(["-c", &format!("command -v {}", bin)]), |bytes| {
     Some(pop_newline(bytes))
   })
 }
 
 fn libc_info() -> Option<String> {
   /*
-  let _: Option<()> = handle_output(Command::new("ldd").args(&["--version"]), |bytes| {
+  let _: Option<()> = handle_output(Command::new("ldd").args(["--version"]), |bytes| {
     println!("output of ldd --version=<{}>", std::str::from_utf8(&bytes).unwrap());
     None
   });
@@ -142,7 +142,7 @@ fn libc_info() -> Option<String> {
 
   if let (Some(rhome), Some(rtoolchain), Ok(target)) = (option_env!("RUSTUP_HOME"), option_env!("RUSTUP_TOOLCHAIN"), std::env::var("TARGET")) {
     let libdir = format!("{}/toolchains/{}/lib/rustlib/{}/lib", rhome, rtoolchain, target);
-    handle_output(Command::new("sh").args(&["-c", &format!("ls -1t {}/libstd*.[sd]*", libdir)]), |bytes| {
+    handle_output(Command::new("sh").args(["-c", &format!("ls -1t {}/libstd*.[sd]*", libdir)]), |bytes| {
       let mut lines = bytes.split(|c| *c == 10 || *c == 13);
       if let Some(first) = lines.next() {
         starting = Some(first.to_vec());
@@ -155,12 +155,12 @@ fn libc_info() -> Option<String> {
     .and_then(|starting| {
       println!("location of starting={:?}", std::str::from_utf8(&starting).unwrap()); // FIXME
       let starting: &std::ffi::OsStr = std::os::unix::ffi::OsStrExt::from_bytes(&starting);
-      handle_output(Command::new("ldd").args(&[starting]), |bytes| {
+      handle_output(Command::new("ldd").args([starting]), |bytes| {
         println!("output of ldd starting={:?}", std::str::from_utf8(&bytes).unwrap()); // FIXME
         let mut lines = bytes.split(|c| *c == 10 || *c == 13);
         loop {
           match lines.next() {
-            Some(line) => match get_words(&line, &[1, 3]).and_then(as_pair) {
+            Some(line) => match get_words(line, &[1, 3]).and_then(as_pair) {
               Some((u, v)) if u.starts_with(b"libc.") => return Some(v.to_vec()),
               Some((u, v)) if u.starts_with(b"libc-") => return Some(v.to_vec()),
               _ => (),
@@ -173,7 +173,7 @@ fn libc_info() -> Option<String> {
     .and_then(|libc| {
       println!("loc of libc={:?}", std::str::from_utf8(&libc).unwrap()); // FIXME
       let libc: &std::ffi::OsStr = std::os::unix::ffi::OsStrExt::from_bytes(&libc);
-      handle_output(Command::new(libc).args(&["--version"]), |bytes| {
+      handle_output(Command::new(libc).args(["--version"]), |bytes| {
         // println!("output of libc --version=<{}>", std::str::from_utf8(&bytes).unwrap()); // FIXME
         String::from_utf8(bytes).ok()
       })
@@ -216,6 +216,7 @@ fn main() {
   */
   println!("cargo:rerun-if-changed=build.rs");
   println!("cargo:rerun-if-changed=src/bytes.rs");
+  println!("cargo:rustc-check-cfg=cfg(MACOS_ATLEAST_10_10, MACOS_ATLEAST_10_13, MACOS_ATLEAST_10_15)");
   match version_info() {
     Some((name, Some(ver))) if &name == b"Mac OS X" => {
       assert!(cfg!(target_os = "macos"));
@@ -244,7 +245,7 @@ fn main() {
         // assert!(cfg!(target_env = "gnu"));
         let start = version.find("version").expect("no version") + 8;
         let mut after = version.find('\n').expect("no endline");
-        if let Some(stop) = version[start..after].find(|c| c != '.' && (c < '0' || c > '9')) {
+        if let Some(stop) = version[start..after].find(|c: char| c != '.' && !c.is_ascii_digit()) {
           after = start + stop
         }
         let (major, minor) = parse_version(&version[start..after]);
@@ -264,7 +265,7 @@ fn main() {
         let start = version.find("Version").expect("no version") + 8;
         let after = version[start..].find('\n').expect("no newline");
         let version = &version[start..start + after].replacen(".", "0", 1);
-        let (major, minor) = parse_version(&version);
+        let (major, minor) = parse_version(version);
         assert!(major >= 101, "expected musl >= 1.1.0 (April 2014)");
         if major >= 102 {
           assert!(major == 102, "musl libc > 1.2.x");
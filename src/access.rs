@@ -6,7 +6,11 @@ use nix::{errno::Errno, /*Error,*/ NixPath, Result};
 use std::os::unix::io::RawFd;
 
 use crate::open::Symlink;
+#[cfg(target_os = "linux")]
+use crate::perm::{mode_rights, Credentials, Rights};
+use bitflags::bitflags;
 pub use nix::unistd::AccessFlags;
+use std::fmt;
 use std::path::PathBuf;
 
 pub fn get_accmode(fd: RawFd, want_read: bool, want_write: bool) -> Result<bool> {
@@ -46,53 +50,387 @@ pub fn get_accmode(fd: RawFd, want_read: bool, want_write: bool) -> Result<bool>
   })
 }
 
+/// How a file descriptor was opened, from the `O_ACCMODE` bits of its status flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccessMode {
+  ReadOnly,
+  WriteOnly,
+  ReadWrite,
+  /// Opened with `O_PATH` (Linux only), so neither readable nor writable.
+  PathOnly,
+}
+
+bitflags! {
+  /// The file status flags that `F_SETFL` can change (on Linux; other systems vary).
+  pub struct StatusFlags: libc::c_int {
+    const O_APPEND = libc::O_APPEND;
+    const O_NONBLOCK = libc::O_NONBLOCK;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "netbsd"))]
+    const O_DIRECT = libc::O_DIRECT;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    const O_NOATIME = libc::O_NOATIME;
+    const O_ASYNC = libc::O_ASYNC;
+  }
+}
+
+/// The access mode and status flags of an open file description (from `F_GETFL`),
+/// together with the descriptor's close-on-exec flag (from `F_GETFD`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FdStatus {
+  pub access: AccessMode,
+  pub flags: StatusFlags,
+  pub cloexec: bool,
+}
+
+// Interprets the O_ACCMODE (and O_PATH) bits of `flags`.
+pub(crate) fn access_mode(flags: libc::c_int) -> AccessMode {
+  match flags & libc::O_ACCMODE {
+    #[cfg(target_os = "linux")]
+    _ if flags & libc::O_PATH != 0 => AccessMode::PathOnly,
+    libc::O_WRONLY => AccessMode::WriteOnly,
+    libc::O_RDWR => AccessMode::ReadWrite,
+    _ => AccessMode::ReadOnly,
+  }
+}
+
+/// Reads the access mode, status flags and close-on-exec flag of `fd`, which may be an `O_PATH` descriptor.
+pub fn get_status(fd: RawFd) -> Result<FdStatus> {
+  let flags = Errno::result(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+  let fdflags = Errno::result(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+  Ok(FdStatus {
+    access: access_mode(flags),
+    flags: StatusFlags::from_bits_truncate(flags),
+    cloexec: fdflags & libc::FD_CLOEXEC != 0,
+  })
+}
+
+/// Sets (if `value` is true) or clears the status flags `flags` of `fd`, leaving the others as they were.
+///
+/// Nothing is written if the flags already have that value. Otherwise, this fails with
+/// EBADF for an `O_PATH` descriptor, and with EPERM when setting `O_NOATIME` on a file the
+/// caller doesn't own. Note that the status flags belong to the open file description,
+/// so are shared with any duplicates of `fd`.
+pub fn set_status_flags(fd: RawFd, flags: StatusFlags, value: bool) -> Result<()> {
+  let old = Errno::result(unsafe { libc::fcntl(fd, libc::F_GETFL) })?;
+  let new = if value { old | flags.bits() } else { old & !flags.bits() };
+  if new == old {
+    return Ok(());
+  }
+  let res = unsafe { libc::fcntl(fd, libc::F_SETFL, new) };
+  Errno::result(res).map(drop)
+}
+
+/// Sets or clears the close-on-exec flag of `fd`, leaving any other descriptor flags as they were.
+pub fn set_cloexec(fd: RawFd, value: bool) -> Result<()> {
+  let old = Errno::result(unsafe { libc::fcntl(fd, libc::F_GETFD) })?;
+  let new = if value {
+    old | libc::FD_CLOEXEC
+  } else {
+    old & !libc::FD_CLOEXEC
+  };
+  if new == old {
+    return Ok(());
+  }
+  let res = unsafe { libc::fcntl(fd, libc::F_SETFD, new) };
+  Errno::result(res).map(drop)
+}
+
 // Based on https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/fs.rs
 // Windows Vista+ have GetFinalPathNameByHandle or GetFileInformationByHandleEx, passing FileNameInfo: https://stackoverflow.com/a/1188803/272427
 
-pub fn get_path(fd: RawFd) -> Option<PathBuf> {
-  use nix::unistd::getcwd;
+/// What a file descriptor refers to, as `get_path` reports it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FdTarget {
+  /// A file that has the given name.
+  Path(PathBuf),
+  /// A file that was unlinked after being opened; this was its last name.
+  DeletedPath(PathBuf),
+  /// A pipe or FIFO with no name, and its inode number.
+  Pipe(u64),
+  /// A socket, and its inode number.
+  Socket(u64),
+  /// An anonymous inode, such as an eventfd or epoll instance, by its kind (like "eventfd").
+  AnonInode(String),
+  /// A file from `memfd_create`, by the name it was given.
+  Memfd(String),
+  /// Something else, as the kernel describes it (like "net:[4026531840]" for a namespace).
+  Unknown(PathBuf),
+}
+
+#[cfg(target_os = "linux")]
+fn bracketed_ino(text: &[u8], prefix: &[u8]) -> Option<u64> {
+  if text.starts_with(prefix) && text.ends_with(b"]") {
+    std::str::from_utf8(&text[prefix.len()..text.len() - 1])
+      .ok()?
+      .parse()
+      .ok()
+  } else {
+    None
+  }
+}
+
+// Whether `name`, as readlink gave it, still names the file that `file` describes.
+#[cfg(target_os = "linux")]
+pub(crate) fn names_file(name: &[u8], file: Result<nix::sys::stat::FileStat>) -> bool {
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::fstatat;
+  use std::ffi::OsStr;
+  use std::os::unix::ffi::OsStrExt;
+  match (
+    file,
+    fstatat(libc::AT_FDCWD, OsStr::from_bytes(name), AtFlags::AT_SYMLINK_NOFOLLOW),
+  ) {
+    (Ok(file), Ok(st)) => st.st_dev == file.st_dev && st.st_ino == file.st_ino,
+    _ => false,
+  }
+}
+
+// Interprets what readlink gives for /proc/<pid>/fd/N. The predicates are asked only about
+// names ending with " (deleted)": whether that whole name still names the file (see
+// `names_file`), and if not whether it's a memfd.
+#[cfg(target_os = "linux")]
+pub(crate) fn classify_target<N, M>(target: Vec<u8>, is_named: N, is_memfd: M) -> FdTarget
+where
+  N: FnOnce(&[u8]) -> bool,
+  M: FnOnce() -> bool,
+{
   use std::ffi::OsString;
   use std::os::unix::ffi::OsStringExt;
+  const DELETED: &[u8] = b" (deleted)";
+  let to_path = |bytes: Vec<u8>| PathBuf::from(OsString::from_vec(bytes));
+  if !target.starts_with(b"/") {
+    if let Some(ino) = bracketed_ino(&target, b"pipe:[") {
+      return FdTarget::Pipe(ino);
+    }
+    if let Some(ino) = bracketed_ino(&target, b"socket:[") {
+      return FdTarget::Socket(ino);
+    }
+    if target.starts_with(b"anon_inode:") {
+      let kind = &target[b"anon_inode:".len()..];
+      let kind = if kind.starts_with(b"[") && kind.ends_with(b"]") {
+        &kind[1..kind.len() - 1]
+      } else {
+        kind
+      };
+      return FdTarget::AnonInode(String::from_utf8_lossy(kind).into_owned());
+    }
+    return FdTarget::Unknown(to_path(target));
+  }
+  // a name can itself end with " (deleted)"; the link count can't tell, since a file
+  // that's been hard-linked elsewhere keeps its links after this name is removed
+  if target.ends_with(DELETED) && !is_named(&target) {
+    let name = &target[..target.len() - DELETED.len()];
+    if name.starts_with(b"/memfd:") && is_memfd() {
+      return FdTarget::Memfd(String::from_utf8_lossy(&name[b"/memfd:".len()..]).into_owned());
+    }
+    return FdTarget::DeletedPath(to_path(name.to_vec()));
+  }
+  FdTarget::Path(to_path(target))
+}
+
+/// Reports what `fd` refers to: the name of the file it has open if there is one, or
+/// else what kind of object it is.
+///
+/// `libc::AT_FDCWD` gives the current working directory. On Linux this reads the
+/// /proc/self/fd link, on macos and netbsd it uses `F_GETPATH` (and only gives `Path`).
+/// For a directory, when /proc isn't mounted or elsewhere, it uses `walk_dir_path`.
+/// Otherwise it fails with `UnsupportedOperation` (or on Linux, ENOENT).
+pub fn get_path(fd: RawFd) -> Result<FdTarget> {
+  use nix::unistd::getcwd;
   if fd == libc::AT_FDCWD {
-    return getcwd().ok();
+    return getcwd().map(FdTarget::Path);
   }
   #[cfg(target_os = "linux")]
   {
-    use libc::{c_char, size_t};
-    use std::ffi::CStr;
-    let mut path = PathBuf::from("/proc/self/fd");
-    path.push(fd.to_string());
-    // nix::fcntl::readlink(&path).ok().map(PathBuf::from)
-    let mut buf = Vec::with_capacity(libc::PATH_MAX as usize);
-    let mut path_vec = path.into_os_string().into_vec();
-    path_vec.push(0);
-    let res = unsafe {
-      let cstr = CStr::from_bytes_with_nul_unchecked(&path_vec);
-      libc::readlink(cstr.as_ptr(), buf.as_mut_ptr() as *mut c_char, buf.capacity() as size_t)
+    use crate::fd::proc_self_fd;
+    use crate::open::readlinkat_bytes;
+    let target = match readlinkat_bytes(libc::AT_FDCWD, proc_self_fd(fd).as_c_str()) {
+      Ok(target) => target,
+      // /proc isn't mounted, as in many chroots
+      Err(nix::Error::Sys(Errno::ENOENT)) if is_dir(fd) => return walk_dir_path(fd).map(FdTarget::Path),
+      Err(e) => return Err(e),
     };
-    if res == -1 {
-      None
-    } else {
-      unsafe { buf.set_len(res as usize) };
-      Some(PathBuf::from(OsString::from_vec(buf)))
-    }
+    Ok(classify_target(
+      target,
+      |name| names_file(name, nix::sys::stat::fstat(fd)),
+      // only memfds support sealing
+      || unsafe { libc::fcntl(fd, libc::F_GET_SEALS) } != -1,
+    ))
   }
   #[cfg(any(target_os = "macos", target_os = "netbsd"))]
   {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
     let mut buf = vec![0; libc::PATH_MAX as usize];
     let res = unsafe { libc::fcntl(fd, libc::F_GETPATH, buf.as_ptr()) };
-    if res == -1 {
-      None
-    } else {
-      let len = buf.iter().position(|&c| c == 0).unwrap();
-      buf.truncate(len as usize);
-      buf.shrink_to_fit();
-      Some(PathBuf::from(OsString::from_vec(buf)))
-    }
+    Errno::result(res)?;
+    let len = buf.iter().position(|&c| c == 0).unwrap();
+    buf.truncate(len as usize);
+    buf.shrink_to_fit();
+    Ok(FdTarget::Path(PathBuf::from(OsString::from_vec(buf))))
   }
   #[cfg(not(any(target_os = "linux", target_os = "macos", target_os = "netbsd")))]
   {
-    None
+    use nix::Error;
+    if is_dir(fd) {
+      return walk_dir_path(fd).map(FdTarget::Path);
+    }
+    Err(Error::UnsupportedOperation)
+  }
+}
+
+fn is_dir(fd: RawFd) -> bool {
+  matches!(nix::sys::stat::fstat(fd), Ok(ref st) if st.st_mode & libc::S_IFMT == libc::S_IFDIR)
+}
+
+// Looks in `parent` for the entry that `child` describes. With `by_ino`, only entries
+// whose d_ino matches are examined; that misses mount points, whose d_ino is the inode
+// of the directory underneath.
+#[allow(clippy::unnecessary_cast)] // ino_t isn't u64 everywhere
+fn find_entry(parent: RawFd, child: &nix::sys::stat::FileStat, by_ino: bool) -> Result<Option<Vec<u8>>> {
+  use nix::dir::Dir;
+  use nix::fcntl::{AtFlags, OFlag};
+  use nix::sys::stat::{fstatat, Mode};
+  let mut dir = Dir::openat(parent, ".", OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+  for entry in dir.iter() {
+    let entry = entry?;
+    let name = entry.file_name();
+    if name.to_bytes() == b"." || name.to_bytes() == b".." {
+      continue;
+    }
+    if by_ino && entry.ino() != child.st_ino as u64 {
+      continue;
+    }
+    let st = fstatat(parent, name, AtFlags::AT_SYMLINK_NOFOLLOW);
+    if matches!(st, Ok(ref st) if st.st_dev == child.st_dev && st.st_ino == child.st_ino) {
+      return Ok(Some(name.to_bytes().to_vec()));
+    }
+  }
+  Ok(None)
+}
+
+/// Finds a name for the directory `fd` without help from the kernel, as `getcwd` once
+/// did: by opening ".." repeatedly, looking in each parent for the entry with the same
+/// device and inode as the child, until reaching a directory that is its own parent.
+///
+/// This works where `get_path` can't (it falls back to this for directories), but needs
+/// read and search permission for every ancestor. It fails with ENOTDIR if `fd` isn't a
+/// directory, and with ENOENT if the directory has been removed or can't be reached
+/// from the root (say, it's outside the current chroot).
+pub fn walk_dir_path(fd: RawFd) -> Result<PathBuf> {
+  use crate::open::{openat, OFlag};
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::{fstat, fstatat, Mode};
+  use nix::Error;
+  use std::ffi::OsStr;
+  use std::os::unix::ffi::OsStrExt;
+  let open_parent = |dirfd| {
+    openat(
+      Some(dirfd),
+      "..",
+      OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+      Mode::empty(),
+      Symlink::Follow,
+    )
+  };
+  let mut child = if fd == libc::AT_FDCWD {
+    fstatat(fd, ".", AtFlags::empty())?
+  } else {
+    fstat(fd)?
+  };
+  if child.st_mode & libc::S_IFMT != libc::S_IFDIR {
+    return Err(Error::Sys(Errno::ENOTDIR));
+  }
+  let mut names = Vec::new();
+  let mut parent = open_parent(fd)?;
+  loop {
+    let st = fstat(parent.as_raw())?;
+    if st.st_dev == child.st_dev && st.st_ino == child.st_ino {
+      break;
+    }
+    let name = match find_entry(parent.as_raw(), &child, st.st_dev == child.st_dev)? {
+      Some(name) => Some(name),
+      None if st.st_dev == child.st_dev => find_entry(parent.as_raw(), &child, false)?,
+      None => None,
+    };
+    match name {
+      Some(name) => names.push(name),
+      None => return Err(Error::Sys(Errno::ENOENT)),
+    }
+    child = st;
+    parent = open_parent(parent.as_raw())?;
+  }
+  let mut path = PathBuf::from("/");
+  for name in names.iter().rev() {
+    path.push(OsStr::from_bytes(name));
+  }
+  Ok(path)
+}
+
+/// Why `verified_path` couldn't vouch for a descriptor's name.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PathError {
+  /// Nothing has the name any more, but the file still has links elsewhere.
+  Renamed(PathBuf),
+  /// The file has no links left; this was its last name.
+  Deleted(PathBuf),
+  /// The name now belongs to a different file.
+  Replaced(PathBuf),
+  /// The descriptor doesn't refer to a named file (it's a pipe, socket and so on).
+  Unnamed(FdTarget),
+  /// Some other failure, from `get_path` or from examining the file.
+  Sys(nix::Error),
+}
+
+impl fmt::Display for PathError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      PathError::Renamed(path) => write!(f, "{} no longer names the file: it was renamed", path.display()),
+      PathError::Deleted(path) => write!(f, "{} no longer names the file: it was deleted", path.display()),
+      PathError::Replaced(path) => write!(f, "{} now names a different file", path.display()),
+      PathError::Unnamed(target) => write!(f, "descriptor has no name: {:?}", target),
+      PathError::Sys(e) => e.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for PathError {}
+
+impl From<nix::Error> for PathError {
+  fn from(e: nix::Error) -> Self {
+    PathError::Sys(e)
+  }
+}
+
+// Checks that `path` still names the file that `fd` refers to.
+fn verify_name(fd: RawFd, path: PathBuf) -> std::result::Result<PathBuf, PathError> {
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::{fstat, fstatat};
+  let ours = if fd == libc::AT_FDCWD {
+    fstatat(fd, ".", AtFlags::empty())?
+  } else {
+    fstat(fd)?
+  };
+  // don't follow a final symlink: an O_PATH descriptor may be open on the link itself
+  match fstatat(libc::AT_FDCWD, &path, AtFlags::AT_SYMLINK_NOFOLLOW) {
+    Ok(st) if st.st_dev == ours.st_dev && st.st_ino == ours.st_ino => Ok(path),
+    _ if ours.st_nlink == 0 => Err(PathError::Deleted(path)),
+    Ok(_) => Err(PathError::Replaced(path)),
+    Err(nix::Error::Sys(Errno::ENOENT)) | Err(nix::Error::Sys(Errno::ENOTDIR)) => Err(PathError::Renamed(path)),
+    Err(e) => Err(PathError::Sys(e)),
+  }
+}
+
+/// Like `get_path`, but gives the name only once `fstatat` confirms that it still refers
+/// to the same file (the same device and inode) as `fd` does.
+///
+/// The name `get_path` reports can be stale by the time it's used; this says how. Of
+/// course the name can still change after this returns.
+pub fn verified_path(fd: RawFd) -> std::result::Result<PathBuf, PathError> {
+  match get_path(fd)? {
+    FdTarget::Path(path) => verify_name(fd, path),
+    FdTarget::DeletedPath(path) => Err(PathError::Deleted(path)),
+    target => Err(PathError::Unnamed(target)),
   }
 }
 
@@ -107,6 +445,9 @@ pub fn get_path(fd: RawFd) -> Option<PathBuf> {
 /// If `links` is `Symlink::Open` and `path` names a symbolic link,
 /// then the mode of the symbolic link is queried. (On macos, not available until 10.15.)
 ///
+/// If `links` is `Symlink::Fail` and `path` names a symbolic link, this fails with ELOOP.
+/// (Only available on Linux.)
+///
 /// # References
 ///
 /// [faccessat(2)](http://pubs.opengroup.org/onlinepubs/9699919799/functions/faccessat.html)
@@ -123,9 +464,19 @@ pub fn faccessat<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, mode: Acce
       libc::AT_SYMLINK_NOFOLLOW
     }
     Symlink::Fail => {
-      // TODO
-      use nix::Error;
-      return Err(Error::UnsupportedOperation);
+      #[cfg(target_os = "linux")]
+      {
+        use crate::fd::proc_self_fd;
+        use crate::open::open_nofollow;
+        let fd = open_nofollow(dirfd, path)?;
+        let res = unsafe { libc::faccessat(libc::AT_FDCWD, proc_self_fd(fd.as_raw()).as_ptr(), mode.bits(), 0) };
+        return Errno::result(res).map(drop);
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        use nix::Error;
+        return Err(Error::UnsupportedOperation);
+      }
     }
   };
   let res = path.with_nix_path(|cstr| unsafe {
@@ -134,6 +485,157 @@ pub fn faccessat<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, mode: Acce
   Errno::result(res).map(drop)
 }
 
+#[cfg(target_os = "linux")]
+#[allow(non_upper_case_globals)]
+mod faccessat2_imports {
+  use libc::c_long;
+  #[cfg(target_arch = "mips")]
+  pub const SYS_faccessat2: c_long = 4439;
+  #[cfg(target_arch = "mips64")]
+  pub const SYS_faccessat2: c_long = 5439;
+  #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+  pub const SYS_faccessat2: c_long = 439;
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused_imports)] // shadowed by libc::* when that has it
+use faccessat2_imports::SYS_faccessat2;
+
+#[cfg(target_os = "linux")]
+syscall! {
+    fn sys_faccessat2(
+        SYS_faccessat2,
+        fd: libc::c_int,
+        pathname: *const libc::c_char,
+        mode: libc::c_int,
+        flags: libc::c_int
+    ) -> libc::c_int
+}
+
+#[cfg(target_os = "linux")]
+fn faccessat2_available() -> bool {
+  use std::sync::atomic::AtomicU8;
+  // Linux kernel prior to 5.8 doesn't support `faccessat2`
+  static FACCESSAT2_STATE: AtomicU8 = AtomicU8::new(0);
+  crate::syscall_available(&FACCESSAT2_STATE, || unsafe {
+    sys_faccessat2(libc::AT_FDCWD, std::ptr::null(), 0, 0)
+  })
+}
+
+/// Reports whether the running kernel provides `faccessat2` (Linux 5.8 or later).
+///
+/// The answer is probed once and cached.
+pub fn has_faccessat2() -> bool {
+  #[cfg(target_os = "linux")]
+  {
+    faccessat2_available()
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    false
+  }
+}
+
+// Decides access from the file's mode and owner the way the kernel does for `creds`; see `mode_rights`.
+#[cfg(target_os = "linux")]
+fn emulate_access(stat: &libc::stat, mode: AccessFlags, creds: &Credentials) -> Result<()> {
+  use crate::chown::{Gid, Uid};
+  use nix::Error;
+  let want = Rights::from_bits_truncate(mode.bits() as u8);
+  let (owner, group) = (Uid::from_raw(stat.st_uid), Gid::from_raw(stat.st_gid));
+  let allowed = mode_rights(stat.st_mode, owner, group, creds);
+  if allowed.contains(want) {
+    Ok(())
+  } else {
+    Err(Error::Sys(Errno::EACCES))
+  }
+}
+
+#[cfg(target_os = "linux")]
+fn faccessat2_emulated<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  mode: AccessFlags,
+  flags: libc::c_int,
+) -> Result<()> {
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::fstatat;
+  use nix::unistd::{getegid, geteuid, getgid, getgroups, getuid};
+  let atflags = if flags & libc::AT_SYMLINK_NOFOLLOW != 0 {
+    AtFlags::AT_SYMLINK_NOFOLLOW
+  } else {
+    AtFlags::empty()
+  };
+  let stat = fstatat(dirfd.unwrap_or(libc::AT_FDCWD), path, atflags)?;
+  let (uid, gid) = if flags & libc::AT_EACCESS != 0 {
+    (geteuid(), getegid())
+  } else {
+    (getuid(), getgid())
+  };
+  emulate_access(&stat, mode, &Credentials::new(uid, gid, getgroups()?))
+}
+
+/// Checks the file named by `path` for accessibility like `faccessat`, but with the
+/// semantics of the `faccessat2` syscall.
+///
+/// If `eaccess` is true, the check is made with the effective user and group IDs,
+/// rather than the real ones (`AT_EACCESS`), as a setuid program wants.
+///
+/// `links` is as for `faccessat`; `Symlink::Open` now works with musl as well.
+///
+/// On Linux 5.8 and later this uses the `faccessat2` syscall (see `has_faccessat2`). On
+/// earlier kernels, when `eaccess` or `Symlink::Open` is asked for, it falls back to
+/// comparing the credentials to the file's mode bits; that ignores ACLs and read-only
+/// mounts. Elsewhere, the C library's `faccessat` is used.
+///
+/// # References
+///
+/// [faccessat2(2)](http://man7.org/linux/man-pages/man2/faccessat2.2.html)
+pub fn faccessat2<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  mode: AccessFlags,
+  links: Symlink,
+  eaccess: bool,
+) -> Result<()> {
+  let mut flags = if eaccess { libc::AT_EACCESS } else { 0 };
+  match links {
+    Symlink::Follow => (),
+    Symlink::Open => flags |= libc::AT_SYMLINK_NOFOLLOW,
+    Symlink::Fail => {
+      #[cfg(target_os = "linux")]
+      {
+        use crate::fd::proc_self_fd;
+        use crate::open::open_nofollow;
+        let fd = open_nofollow(dirfd, path)?;
+        let proc_path = proc_self_fd(fd.as_raw());
+        return faccessat2(None, proc_path.as_c_str(), mode, Symlink::Follow, eaccess);
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        use nix::Error;
+        return Err(Error::UnsupportedOperation);
+      }
+    }
+  }
+  #[cfg(target_os = "linux")]
+  {
+    if faccessat2_available() {
+      let res = path.with_nix_path(|cstr| unsafe {
+        sys_faccessat2(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), mode.bits(), flags)
+      })?;
+      return Errno::result(res).map(drop);
+    }
+    if flags != 0 {
+      return faccessat2_emulated(dirfd, path, mode, flags);
+    }
+  }
+  let res = path.with_nix_path(|cstr| unsafe {
+    libc::faccessat(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), mode.bits(), flags)
+  })?;
+  Errno::result(res).map(drop)
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -141,6 +643,236 @@ mod tests {
   use nix::sys::stat::Mode;
   use std::fs::File;
 
+  #[test]
+  fn test_fd_status() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    let fd = open(
+      &path,
+      OFlag::O_CREAT | OFlag::O_WRONLY | OFlag::O_APPEND,
+      Mode::from_bits_truncate(0o644),
+    )
+    .unwrap();
+    let status = get_status(fd).unwrap();
+    assert_eq!(status.access, AccessMode::WriteOnly);
+    assert_eq!(status.flags, StatusFlags::O_APPEND);
+    assert!(!status.cloexec);
+
+    set_status_flags(fd, StatusFlags::O_NONBLOCK, true).unwrap();
+    set_status_flags(fd, StatusFlags::O_APPEND, false).unwrap();
+    set_cloexec(fd, true).unwrap();
+    let status = get_status(fd).unwrap();
+    assert_eq!(status.access, AccessMode::WriteOnly);
+    assert_eq!(status.flags, StatusFlags::O_NONBLOCK);
+    assert!(status.cloexec);
+    set_cloexec(fd, false).unwrap();
+    assert!(!get_status(fd).unwrap().cloexec);
+    nix::unistd::close(fd).unwrap();
+
+    #[cfg(target_os = "linux")]
+    {
+      let fd = open(&path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty()).unwrap();
+      let status = get_status(fd).unwrap();
+      assert_eq!(status.access, AccessMode::PathOnly);
+      assert!(status.cloexec);
+      set_status_flags(fd, StatusFlags::O_APPEND, false).unwrap();
+      nix::unistd::close(fd).unwrap();
+    }
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_faccessat_fail() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    let _file = File::create(&path).unwrap();
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &link).unwrap();
+    let dangling = tempdir.path().join("dangling");
+    std::os::unix::fs::symlink("missing", &dangling).unwrap();
+    let errno = |path: &std::path::Path, mode| faccessat(None, path, mode, Symlink::Fail).err().unwrap().as_errno();
+
+    faccessat(None, &path, AccessFlags::R_OK | AccessFlags::W_OK, Symlink::Fail).unwrap();
+    assert_eq!(errno(&link, AccessFlags::F_OK), Some(Errno::ELOOP));
+    assert_eq!(errno(&dangling, AccessFlags::R_OK), Some(Errno::ELOOP));
+    let missing = tempdir.path().join("missing");
+    assert_eq!(errno(&missing, AccessFlags::F_OK), Some(Errno::ENOENT));
+    assert_eq!(errno(&path, AccessFlags::X_OK), Some(Errno::EACCES));
+  }
+
+  #[test]
+  fn test_faccessat2() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    let _file = File::create(&path).unwrap();
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("missing", &link).unwrap();
+    let rw = AccessFlags::R_OK | AccessFlags::W_OK;
+    for &eaccess in &[false, true] {
+      faccessat2(None, &path, rw, Symlink::Follow, eaccess).unwrap();
+      faccessat2(None, &link, AccessFlags::F_OK, Symlink::Open, eaccess).unwrap();
+      let errno = |path, mode, links| faccessat2(None, path, mode, links, eaccess).err().unwrap().as_errno();
+      assert_eq!(errno(&link, AccessFlags::F_OK, Symlink::Follow), Some(Errno::ENOENT));
+      #[cfg(target_os = "linux")]
+      assert_eq!(errno(&link, AccessFlags::F_OK, Symlink::Fail), Some(Errno::ELOOP));
+      // not even root may execute a file without any execute bits
+      assert_eq!(errno(&path, AccessFlags::X_OK, Symlink::Follow), Some(Errno::EACCES));
+    }
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_faccessat2_emulated() {
+    use crate::chown::{Gid, Uid};
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    let _file = File::create(&path).unwrap();
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("missing", &link).unwrap();
+    let nofollow = libc::AT_SYMLINK_NOFOLLOW;
+    faccessat2_emulated(None, &path, AccessFlags::R_OK | AccessFlags::W_OK, libc::AT_EACCESS).unwrap();
+    faccessat2_emulated(None, &link, AccessFlags::F_OK, nofollow | libc::AT_EACCESS).unwrap();
+    let err = faccessat2_emulated(None, &link, AccessFlags::F_OK, libc::AT_EACCESS).err();
+    assert_eq!(err.unwrap().as_errno(), Some(Errno::ENOENT));
+
+    let mut stat = nix::sys::stat::stat(&path).unwrap();
+    stat.st_uid = 1000;
+    stat.st_gid = 1000;
+    stat.st_mode = libc::S_IFREG | 0o640;
+    let check = |uid, gid, groups: &[libc::gid_t], mode| {
+      let groups = groups.iter().map(|g| Gid::from_raw(*g)).collect();
+      let creds = Credentials::new(Uid::from_raw(uid), Gid::from_raw(gid), groups);
+      emulate_access(&stat, mode, &creds).is_ok()
+    };
+    assert!(check(1000, 5, &[], AccessFlags::R_OK | AccessFlags::W_OK));
+    assert!(!check(1000, 5, &[], AccessFlags::X_OK));
+    assert!(check(2000, 1000, &[], AccessFlags::R_OK));
+    assert!(!check(2000, 1000, &[], AccessFlags::W_OK));
+    assert!(!check(2000, 5, &[7, 1000], AccessFlags::W_OK));
+    assert!(check(2000, 5, &[7, 1000], AccessFlags::R_OK));
+    assert!(!check(2000, 5, &[], AccessFlags::R_OK));
+    assert!(check(2000, 5, &[], AccessFlags::F_OK));
+    assert!(check(0, 0, &[], AccessFlags::R_OK | AccessFlags::W_OK));
+    assert!(!check(0, 0, &[], AccessFlags::X_OK));
+  }
+
+  #[test]
+  fn test_get_path() {
+    use std::os::unix::io::AsRawFd;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file (deleted)");
+    let file = File::create(&path).unwrap();
+    let path = path.canonicalize().unwrap();
+    assert_eq!(get_path(file.as_raw_fd()).unwrap(), FdTarget::Path(path.clone()));
+    assert_eq!(
+      get_path(libc::AT_FDCWD).unwrap(),
+      FdTarget::Path(std::env::current_dir().unwrap())
+    );
+
+    #[cfg(target_os = "linux")]
+    {
+      use nix::sys::stat::fstat;
+      std::fs::remove_file(&path).unwrap();
+      assert_eq!(get_path(file.as_raw_fd()).unwrap(), FdTarget::DeletedPath(path));
+
+      // the file lives on under another name, but the one it was opened by is gone
+      let a = tempdir.path().canonicalize().unwrap().join("a");
+      let file = File::create(&a).unwrap();
+      std::fs::hard_link(&a, tempdir.path().join("b")).unwrap();
+      std::fs::remove_file(&a).unwrap();
+      assert_eq!(get_path(file.as_raw_fd()).unwrap(), FdTarget::DeletedPath(a.clone()));
+      assert_eq!(verified_path(file.as_raw_fd()), Err(PathError::Deleted(a)));
+
+      let (rd, wr) = nix::unistd::pipe().unwrap();
+      let ino = fstat(rd).unwrap().st_ino as u64;
+      assert_eq!(get_path(rd).unwrap(), FdTarget::Pipe(ino));
+      nix::unistd::close(rd).unwrap();
+      nix::unistd::close(wr).unwrap();
+
+      let (sock, _peer) = std::os::unix::net::UnixStream::pair().unwrap();
+      let ino = fstat(sock.as_raw_fd()).unwrap().st_ino as u64;
+      assert_eq!(get_path(sock.as_raw_fd()).unwrap(), FdTarget::Socket(ino));
+
+      let efd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC) };
+      assert_eq!(get_path(efd).unwrap(), FdTarget::AnonInode("eventfd".to_string()));
+      nix::unistd::close(efd).unwrap();
+
+      let name = std::ffi::CString::new("scratch").unwrap();
+      let mfd = unsafe { libc::memfd_create(name.as_ptr(), libc::MFD_CLOEXEC) };
+      assert_eq!(get_path(mfd).unwrap(), FdTarget::Memfd("scratch".to_string()));
+      nix::unistd::close(mfd).unwrap();
+    }
+  }
+
+  #[test]
+  fn test_verified_path() {
+    use std::os::unix::io::AsRawFd;
+    let tempdir = tempfile::tempdir().unwrap();
+    let tempdir = tempdir.path().canonicalize().unwrap();
+    let (old, new, other) = (tempdir.join("old"), tempdir.join("new"), tempdir.join("other"));
+    let file = File::create(&old).unwrap();
+    let fd = file.as_raw_fd();
+    assert_eq!(verified_path(fd), Ok(old.clone()));
+    std::fs::rename(&old, &new).unwrap();
+    assert_eq!(verify_name(fd, old.clone()), Err(PathError::Renamed(old.clone())));
+    assert_eq!(
+      verify_name(fd, old.join("child")),
+      Err(PathError::Renamed(old.join("child")))
+    );
+    File::create(&other).unwrap();
+    assert_eq!(verify_name(fd, other.clone()), Err(PathError::Replaced(other)));
+    assert_eq!(
+      verify_name(libc::AT_FDCWD, new.clone()),
+      Err(PathError::Replaced(new.clone()))
+    );
+    std::fs::remove_file(&new).unwrap();
+    assert_eq!(verify_name(fd, new.clone()), Err(PathError::Deleted(new.clone())));
+    #[cfg(target_os = "linux")]
+    {
+      assert_eq!(verified_path(fd), Err(PathError::Deleted(new)));
+      let (rd, wr) = nix::unistd::pipe().unwrap();
+      assert!(matches!(verified_path(rd), Err(PathError::Unnamed(FdTarget::Pipe(_)))));
+      nix::unistd::close(rd).unwrap();
+      nix::unistd::close(wr).unwrap();
+    }
+    assert_eq!(verified_path(libc::AT_FDCWD), Ok(std::env::current_dir().unwrap()));
+  }
+
+  #[test]
+  fn test_walk_dir_path() {
+    use std::os::unix::io::AsRawFd;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().canonicalize().unwrap().join("a").join("b");
+    std::fs::create_dir_all(&path).unwrap();
+    let dir = File::open(&path).unwrap();
+    assert_eq!(walk_dir_path(dir.as_raw_fd()).unwrap(), path);
+    let root = File::open("/").unwrap();
+    assert_eq!(walk_dir_path(root.as_raw_fd()).unwrap(), PathBuf::from("/"));
+    assert_eq!(walk_dir_path(libc::AT_FDCWD).unwrap(), std::env::current_dir().unwrap());
+    // /proc is on a different device from its parent
+    let proc_self = File::open("/proc/self").unwrap();
+    let proc_self = walk_dir_path(proc_self.as_raw_fd()).unwrap();
+    assert!(proc_self.starts_with("/proc"));
+    assert_eq!(proc_self.components().count(), 3);
+
+    let moved = tempdir.path().join("moved");
+    std::fs::rename(tempdir.path().join("a"), &moved).unwrap();
+    assert_eq!(
+      walk_dir_path(dir.as_raw_fd()).unwrap(),
+      moved.canonicalize().unwrap().join("b")
+    );
+    std::fs::remove_dir(moved.join("b")).unwrap();
+    assert_eq!(
+      walk_dir_path(dir.as_raw_fd()).err().unwrap().as_errno(),
+      Some(Errno::ENOENT)
+    );
+    let file = File::create(tempdir.path().join("file")).unwrap();
+    assert_eq!(
+      walk_dir_path(file.as_raw_fd()).err().unwrap().as_errno(),
+      Some(Errno::ENOTDIR)
+    );
+  }
+
   #[test]
   fn test_faccessat_none_not_existing() {
     let tempdir = tempfile::tempdir().unwrap();
@@ -0,0 +1,553 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, NixPath, Result};
+use std::os::unix::io::RawFd;
+
+use crate::chown::{Gid, Uid};
+use crate::fd::{proc_self_fd, OwnedFd};
+use crate::open::{open_nofollow, openat, Mode, OFlag, Symlink};
+use crate::perm::Rights;
+use nix::sys::stat::mode_t;
+use std::ffi::CString;
+use std::fmt;
+use std::str::FromStr;
+
+/*
+ * The xattr value is a little-endian u32 version (2), followed by 8-byte entries:
+ *   u16 tag, u16 perm, u32 id (ACL_UNDEFINED_ID unless the tag is ACL_USER or ACL_GROUP)
+ * See linux/include/uapi/linux/posix_acl_xattr.h.
+ */
+const ACL_XATTR_VERSION: u32 = 0x0002;
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = 0xffff_ffff;
+
+/// Which of a file's two POSIX ACLs is meant.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AclKind {
+  /// The ACL that governs access to the file (`system.posix_acl_access`).
+  Access,
+  /// The ACL that a directory's new children inherit (`system.posix_acl_default`).
+  Default,
+}
+
+impl AclKind {
+  fn xattr_name(self) -> &'static str {
+    match self {
+      AclKind::Access => "system.posix_acl_access",
+      AclKind::Default => "system.posix_acl_default",
+    }
+  }
+}
+
+/// Whom an ACL entry applies to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum AclTag {
+  /// The file's owner.
+  UserObj,
+  User(Uid),
+  /// The file's group.
+  GroupObj,
+  Group(Gid),
+  /// The most that `User`, `GroupObj` and `Group` entries can grant.
+  Mask,
+  Other,
+}
+
+impl AclTag {
+  // The order entries are kept in, as the kernel expects.
+  fn sort_key(&self) -> (u16, u32) {
+    match *self {
+      AclTag::UserObj => (ACL_USER_OBJ, 0),
+      AclTag::User(uid) => (ACL_USER, uid.as_raw()),
+      AclTag::GroupObj => (ACL_GROUP_OBJ, 0),
+      AclTag::Group(gid) => (ACL_GROUP, gid.as_raw()),
+      AclTag::Mask => (ACL_MASK, 0),
+      AclTag::Other => (ACL_OTHER, 0),
+    }
+  }
+
+  // Entries of the group class are the ones limited by the mask.
+  fn in_group_class(&self) -> bool {
+    matches!(*self, AclTag::User(_) | AclTag::GroupObj | AclTag::Group(_))
+  }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AclEntry {
+  pub tag: AclTag,
+  pub perm: Rights,
+}
+
+/// A POSIX access or default ACL.
+///
+/// Entries are kept in the kernel's order, with at most one for each tag; `set` replaces
+/// any existing entry with the same tag. An ACL is only `valid` with `UserObj`, `GroupObj`
+/// and `Other` entries, plus a `Mask` entry if it has any `User` or `Group` entries.
+///
+/// The text form is that of getfacl(1), with numeric ids; parsing also accepts user and
+/// group names, the abbreviated tags `u`, `g`, `m` and `o`, and comments.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Acl {
+  entries: Vec<AclEntry>,
+}
+
+fn perm_from_bits(bits: u16) -> Result<Rights> {
+  if bits & !0o7 != 0 {
+    return Err(Error::Sys(Errno::EINVAL));
+  }
+  Ok(Rights::from_bits_truncate(bits as u8))
+}
+
+impl Acl {
+  /// An empty ACL, to which entries can be added with `set`.
+  pub fn new() -> Self {
+    Acl { entries: Vec::new() }
+  }
+
+  /// The minimal ACL equivalent to the permission bits of `mode`.
+  pub fn from_mode(mode: mode_t) -> Self {
+    let mut acl = Acl::new();
+    acl.set(AclTag::UserObj, Rights::from_bits_truncate(((mode >> 6) & 0o7) as u8));
+    acl.set(AclTag::GroupObj, Rights::from_bits_truncate(((mode >> 3) & 0o7) as u8));
+    acl.set(AclTag::Other, Rights::from_bits_truncate((mode & 0o7) as u8));
+    acl
+  }
+
+  /// The permission bits that correspond to the ACL, as the kernel sets them in a file's
+  /// mode: the group bits come from the mask if there is one.
+  pub fn to_mode(&self) -> mode_t {
+    let bits = |tag| self.get(tag).map_or(0, |perm| perm.bits() as mode_t);
+    let group = if self.get(AclTag::Mask).is_some() {
+      bits(AclTag::Mask)
+    } else {
+      bits(AclTag::GroupObj)
+    };
+    (bits(AclTag::UserObj) << 6) | (group << 3) | bits(AclTag::Other)
+  }
+
+  pub fn entries(&self) -> &[AclEntry] {
+    &self.entries
+  }
+
+  pub fn get(&self, tag: AclTag) -> Option<Rights> {
+    self.entries.iter().find(|e| e.tag == tag).map(|e| e.perm)
+  }
+
+  /// Adds an entry for `tag`, or replaces the one there is.
+  pub fn set(&mut self, tag: AclTag, perm: Rights) {
+    let key = tag.sort_key();
+    match self.entries.binary_search_by_key(&key, |e| e.tag.sort_key()) {
+      Ok(pos) => self.entries[pos].perm = perm,
+      Err(pos) => self.entries.insert(pos, AclEntry { tag, perm }),
+    }
+  }
+
+  pub fn remove(&mut self, tag: AclTag) -> Option<Rights> {
+    let pos = self.entries.iter().position(|e| e.tag == tag)?;
+    Some(self.entries.remove(pos).perm)
+  }
+
+  /// Whether the ACL can be applied: see the type's description.
+  pub fn valid(&self) -> bool {
+    let has = |tag| self.get(tag).is_some();
+    let named = self
+      .entries
+      .iter()
+      .any(|e| matches!(e.tag, AclTag::User(_) | AclTag::Group(_)));
+    has(AclTag::UserObj) && has(AclTag::GroupObj) && has(AclTag::Other) && (!named || has(AclTag::Mask))
+  }
+
+  /// Whether the ACL says no more than the permission bits can.
+  pub fn is_minimal(&self) -> bool {
+    self.entries.len() == 3 && self.valid()
+  }
+
+  /// The union of what the `User`, `GroupObj` and `Group` entries grant, which is what
+  /// setfacl(1) sets the mask to unless told otherwise.
+  pub fn calc_mask(&self) -> Rights {
+    self
+      .entries
+      .iter()
+      .filter(|e| e.tag.in_group_class())
+      .fold(Rights::empty(), |mask, e| mask | e.perm)
+  }
+
+  /// Sets the `Mask` entry to `calc_mask`, if there are named entries or already a mask.
+  pub fn update_mask(&mut self) {
+    let named = self
+      .entries
+      .iter()
+      .any(|e| matches!(e.tag, AclTag::User(_) | AclTag::Group(_)));
+    if named || self.get(AclTag::Mask).is_some() {
+      let mask = self.calc_mask();
+      self.set(AclTag::Mask, mask);
+    }
+  }
+
+  /// What `entry` actually grants, once limited by the mask.
+  pub fn effective(&self, entry: &AclEntry) -> Rights {
+    match self.get(AclTag::Mask) {
+      Some(mask) if entry.tag.in_group_class() => entry.perm & mask,
+      _ => entry.perm,
+    }
+  }
+
+  /// Decodes the value of a `system.posix_acl_*` xattr, failing with EINVAL if it's malformed.
+  pub fn from_xattr(value: &[u8]) -> Result<Self> {
+    use std::convert::TryInto;
+    if value.len() < 4 || u32::from_le_bytes(value[..4].try_into().unwrap()) != ACL_XATTR_VERSION {
+      return Err(Error::Sys(Errno::EINVAL));
+    }
+    let chunks = value[4..].chunks_exact(8);
+    if !chunks.remainder().is_empty() {
+      return Err(Error::Sys(Errno::EINVAL));
+    }
+    let mut acl = Acl::new();
+    for chunk in chunks {
+      let tag = u16::from_le_bytes(chunk[0..2].try_into().unwrap());
+      let perm = perm_from_bits(u16::from_le_bytes(chunk[2..4].try_into().unwrap()))?;
+      let id = u32::from_le_bytes(chunk[4..8].try_into().unwrap());
+      let tag = match tag {
+        ACL_USER_OBJ => AclTag::UserObj,
+        ACL_USER => AclTag::User(Uid::from_raw(id)),
+        ACL_GROUP_OBJ => AclTag::GroupObj,
+        ACL_GROUP => AclTag::Group(Gid::from_raw(id)),
+        ACL_MASK => AclTag::Mask,
+        ACL_OTHER => AclTag::Other,
+        _ => return Err(Error::Sys(Errno::EINVAL)),
+      };
+      if acl.get(tag).is_some() {
+        return Err(Error::Sys(Errno::EINVAL));
+      }
+      acl.set(tag, perm);
+    }
+    Ok(acl)
+  }
+
+  /// Encodes the ACL as the value of a `system.posix_acl_*` xattr.
+  pub fn to_xattr(&self) -> Vec<u8> {
+    let mut value = Vec::with_capacity(4 + 8 * self.entries.len());
+    value.extend_from_slice(&ACL_XATTR_VERSION.to_le_bytes());
+    for entry in &self.entries {
+      let (tag, id) = match entry.tag {
+        AclTag::User(uid) => (ACL_USER, uid.as_raw()),
+        AclTag::Group(gid) => (ACL_GROUP, gid.as_raw()),
+        tag => (tag.sort_key().0, ACL_UNDEFINED_ID),
+      };
+      value.extend_from_slice(&tag.to_le_bytes());
+      value.extend_from_slice(&u16::from(entry.perm.bits()).to_le_bytes());
+      value.extend_from_slice(&id.to_le_bytes());
+    }
+    value
+  }
+}
+
+fn render_perm(perm: Rights) -> String {
+  let bit = |flag, c| if perm.contains(flag) { c } else { '-' };
+  [bit(Rights::READ, 'r'), bit(Rights::WRITE, 'w'), bit(Rights::EXEC, 'x')]
+    .iter()
+    .collect()
+}
+
+fn parse_perm(text: &str) -> Result<Rights> {
+  let mut perm = Rights::empty();
+  for c in text.chars() {
+    perm |= match c {
+      'r' => Rights::READ,
+      'w' => Rights::WRITE,
+      'x' => Rights::EXEC,
+      '-' => Rights::empty(),
+      _ => return Err(Error::Sys(Errno::EINVAL)),
+    };
+  }
+  Ok(perm)
+}
+
+fn parse_uid(text: &str) -> Result<Uid> {
+  if let Ok(id) = text.parse() {
+    return Ok(Uid::from_raw(id));
+  }
+  match nix::unistd::User::from_name(text)? {
+    Some(user) => Ok(user.uid),
+    None => Err(Error::Sys(Errno::EINVAL)),
+  }
+}
+
+fn parse_gid(text: &str) -> Result<Gid> {
+  if let Ok(id) = text.parse() {
+    return Ok(Gid::from_raw(id));
+  }
+  match nix::unistd::Group::from_name(text)? {
+    Some(group) => Ok(group.gid),
+    None => Err(Error::Sys(Errno::EINVAL)),
+  }
+}
+
+impl fmt::Display for Acl {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    for entry in &self.entries {
+      match entry.tag {
+        AclTag::UserObj => write!(f, "user::")?,
+        AclTag::User(uid) => write!(f, "user:{}:", uid)?,
+        AclTag::GroupObj => write!(f, "group::")?,
+        AclTag::Group(gid) => write!(f, "group:{}:", gid)?,
+        AclTag::Mask => write!(f, "mask::")?,
+        AclTag::Other => write!(f, "other::")?,
+      }
+      write!(f, "{}", render_perm(entry.perm))?;
+      let effective = self.effective(entry);
+      if effective != entry.perm {
+        write!(f, "\t#effective:{}", render_perm(effective))?;
+      }
+      writeln!(f)?;
+    }
+    Ok(())
+  }
+}
+
+impl FromStr for Acl {
+  type Err = Error;
+
+  /// Parses the getfacl(1) text form, failing with EINVAL if it's malformed or names an
+  /// unknown user or group, or repeats an entry.
+  fn from_str(text: &str) -> Result<Self> {
+    let mut acl = Acl::new();
+    for line in text.lines() {
+      let line = line.split('#').next().unwrap().trim();
+      if line.is_empty() {
+        continue;
+      }
+      let fields: Vec<&str> = line.split(':').map(str::trim).collect();
+      let (tag, qualifier, perm) = match fields[..] {
+        [tag, qualifier, perm] => (tag, qualifier, perm),
+        // setfacl also accepts "mask:rwx" and "other:r-x"
+        [tag, perm] => (tag, "", perm),
+        _ => return Err(Error::Sys(Errno::EINVAL)),
+      };
+      let tag = match (tag, qualifier) {
+        ("user", "") | ("u", "") => AclTag::UserObj,
+        ("user", name) | ("u", name) => AclTag::User(parse_uid(name)?),
+        ("group", "") | ("g", "") => AclTag::GroupObj,
+        ("group", name) | ("g", name) => AclTag::Group(parse_gid(name)?),
+        ("mask", "") | ("m", "") => AclTag::Mask,
+        ("other", "") | ("o", "") => AclTag::Other,
+        _ => return Err(Error::Sys(Errno::EINVAL)),
+      };
+      if acl.get(tag).is_some() {
+        return Err(Error::Sys(Errno::EINVAL));
+      }
+      acl.set(tag, parse_perm(perm)?);
+    }
+    Ok(acl)
+  }
+}
+
+// Opens `path` without needing any access to it, applying the `links` policy, so that the
+// xattr calls can then go through the /proc magic link to exactly that inode.
+fn open_node<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, links: Symlink) -> Result<OwnedFd> {
+  match links {
+    Symlink::Follow => openat(dirfd, path, OFlag::O_PATH | OFlag::O_CLOEXEC, Mode::empty(), links),
+    Symlink::Open => openat(dirfd, path, OFlag::O_CLOEXEC, Mode::empty(), links),
+    Symlink::Fail => open_nofollow(dirfd, path),
+  }
+}
+
+fn getxattr_bytes(path: &CString, name: &CString) -> Result<Vec<u8>> {
+  loop {
+    let res = unsafe { libc::getxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+    let mut buf = vec![0u8; Errno::result(res)? as usize];
+    let res = unsafe {
+      libc::getxattr(
+        path.as_ptr(),
+        name.as_ptr(),
+        buf.as_mut_ptr() as *mut libc::c_void,
+        buf.len(),
+      )
+    };
+    match Errno::result(res) {
+      Ok(len) => {
+        buf.truncate(len as usize);
+        return Ok(buf);
+      }
+      // it grew in between
+      Err(Error::Sys(Errno::ERANGE)) => continue,
+      Err(e) => return Err(e),
+    }
+  }
+}
+
+/// Reads the ACL of kind `kind` of the file named by `path`, or `None` if it has none.
+///
+/// (A file without an access ACL is governed by its permission bits; see `Acl::from_mode`.)
+///
+/// If `dirfd` has a value, then `path` is relative to directory associated with the file descriptor.
+///
+/// If `dirfd` is `None`, then `path` is relative to the current working directory.
+///
+/// `links` says what to do when `path` names a symlink, which has no ACLs of its own.
+pub fn get_acl<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  kind: AclKind,
+  links: Symlink,
+) -> Result<Option<Acl>> {
+  let fd = open_node(dirfd, path, links)?;
+  let name = CString::new(kind.xattr_name()).unwrap();
+  match getxattr_bytes(&proc_self_fd(fd.as_raw()), &name) {
+    Ok(value) => Acl::from_xattr(&value).map(Some),
+    Err(Error::Sys(Errno::ENODATA)) => Ok(None),
+    Err(e) => Err(e),
+  }
+}
+
+/// Sets the ACL of kind `kind` of the file named by `path`; see `get_acl`.
+///
+/// Fails with EINVAL if `acl` isn't `valid`. Setting an access ACL also changes the file's
+/// permission bits (see `Acl::to_mode`); only directories can have default ACLs.
+pub fn set_acl<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  kind: AclKind,
+  acl: &Acl,
+  links: Symlink,
+) -> Result<()> {
+  if !acl.valid() {
+    return Err(Error::Sys(Errno::EINVAL));
+  }
+  let fd = open_node(dirfd, path, links)?;
+  let name = CString::new(kind.xattr_name()).unwrap();
+  let value = acl.to_xattr();
+  let proc_path = proc_self_fd(fd.as_raw());
+  let res = unsafe {
+    libc::setxattr(
+      proc_path.as_ptr(),
+      name.as_ptr(),
+      value.as_ptr() as *const libc::c_void,
+      value.len(),
+      0,
+    )
+  };
+  Errno::result(res).map(drop)
+}
+
+/// Removes the ACL of kind `kind` of the file named by `path`, if it has one; see `get_acl`.
+pub fn remove_acl<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, kind: AclKind, links: Symlink) -> Result<()> {
+  let fd = open_node(dirfd, path, links)?;
+  let name = CString::new(kind.xattr_name()).unwrap();
+  let res = unsafe { libc::removexattr(proc_self_fd(fd.as_raw()).as_ptr(), name.as_ptr()) };
+  match Errno::result(res) {
+    Err(Error::Sys(Errno::ENODATA)) => Ok(()),
+    res => res.map(drop),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const TEXT: &str =
+    "user::rw-\nuser:1000:rwx\t#effective:r-x\ngroup::r--\ngroup:100:-w-\t#effective:---\nmask::r-x\nother::---\n";
+
+  #[test]
+  fn test_acl_text_and_xattr() {
+    let acl: Acl = TEXT.parse().unwrap();
+    assert!(acl.valid());
+    assert!(!acl.is_minimal());
+    assert_eq!(acl.to_string(), TEXT);
+    assert_eq!(Acl::from_xattr(&acl.to_xattr()).unwrap(), acl);
+    assert_eq!(acl.to_mode(), 0o650);
+    assert_eq!(acl.calc_mask(), Rights::all());
+
+    // entries come out in the kernel's order whatever order they went in
+    let shuffled: Acl = "# file: x\no::-\nm::rx\ng:100:w\ng::r\nu:1000:rwx\nu::rw\n"
+      .parse()
+      .unwrap();
+    assert_eq!(shuffled, acl);
+    assert_eq!(&acl.to_xattr()[..12], &[2, 0, 0, 0, 1, 0, 6, 0, 0xff, 0xff, 0xff, 0xff]);
+
+    let mut updated = acl.clone();
+    updated.update_mask();
+    assert_eq!(updated.get(AclTag::Mask), Some(Rights::all()));
+    assert_eq!(updated.remove(AclTag::Mask), Some(Rights::all()));
+    assert!(!updated.valid());
+
+    let minimal = Acl::from_mode(0o754);
+    assert!(minimal.is_minimal());
+    assert_eq!(minimal.to_string(), "user::rwx\ngroup::r-x\nother::r--\n");
+    assert_eq!(minimal.to_mode(), 0o754);
+
+    for bad in &[
+      "user::rw-\nuser::r--\n",
+      "user:1000\n",
+      "mask:1:rwx\n",
+      "other::rwq\n",
+      "bogus::rwx\n",
+    ] {
+      assert_eq!(bad.parse::<Acl>().err().unwrap().as_errno(), Some(Errno::EINVAL));
+    }
+    for bad in &[
+      &[2u8, 0, 0][..],
+      &[1, 0, 0, 0],
+      &[2, 0, 0, 0, 0x40, 0, 0, 0, 0, 0, 0, 0],
+    ] {
+      assert_eq!(Acl::from_xattr(bad).err().unwrap().as_errno(), Some(Errno::EINVAL));
+    }
+  }
+
+  #[test]
+  fn test_get_set_acl() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    std::fs::File::create(&path).unwrap();
+    let acl: Acl = TEXT.parse().unwrap();
+    match set_acl(None, &path, AclKind::Access, &acl, Symlink::Follow) {
+      Err(Error::Sys(Errno::EOPNOTSUPP)) => {
+        eprintln!("filesystem doesn't support ACLs, skipping");
+        return;
+      }
+      res => res.unwrap(),
+    }
+    assert_eq!(
+      get_acl(None, &path, AclKind::Access, Symlink::Follow).unwrap(),
+      Some(acl.clone())
+    );
+    assert_eq!(nix::sys::stat::stat(&path).unwrap().st_mode & 0o777, 0o650);
+    assert_eq!(get_acl(None, &path, AclKind::Default, Symlink::Follow).unwrap(), None);
+    let err = set_acl(None, &path, AclKind::Default, &acl, Symlink::Follow)
+      .err()
+      .unwrap();
+    assert_eq!(err.as_errno(), Some(Errno::EACCES));
+
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &link).unwrap();
+    assert_eq!(
+      get_acl(None, &link, AclKind::Access, Symlink::Follow).unwrap(),
+      Some(acl)
+    );
+    let err = get_acl(None, &link, AclKind::Access, Symlink::Fail).err().unwrap();
+    assert_eq!(err.as_errno(), Some(Errno::ELOOP));
+
+    set_acl(
+      None,
+      tempdir.path(),
+      AclKind::Default,
+      &Acl::from_mode(0o750),
+      Symlink::Follow,
+    )
+    .unwrap();
+    let dir_acl = get_acl(None, tempdir.path(), AclKind::Default, Symlink::Follow).unwrap();
+    assert_eq!(dir_acl, Some(Acl::from_mode(0o750)));
+    remove_acl(None, tempdir.path(), AclKind::Default, Symlink::Follow).unwrap();
+    remove_acl(None, tempdir.path(), AclKind::Default, Symlink::Follow).unwrap();
+    assert_eq!(
+      get_acl(None, tempdir.path(), AclKind::Default, Symlink::Follow).unwrap(),
+      None
+    );
+  }
+}
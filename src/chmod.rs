@@ -0,0 +1,51 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Result};
+use std::os::unix::io::RawFd;
+
+use crate::open::Mode;
+
+/// Change the permission bits of the file that `fd` refers to (see
+/// [fchmod(2)](https://pubs.opengroup.org/onlinepubs/9699919799/functions/fchmod.html)).
+///
+/// On Linux, `fd` may be an `O_PATH` descriptor, in which case the change is made through
+/// /proc/self/fd. Symlinks have no permissions of their own, so that fails with EOPNOTSUPP
+/// for a descriptor opened on one with `Symlink::Open`.
+pub fn fchmod(fd: RawFd, mode: Mode) -> Result<()> {
+  let res = unsafe { libc::fchmod(fd, mode.bits()) };
+  #[cfg(target_os = "linux")]
+  {
+    use crate::fd::{is_opath, proc_self_fd};
+    if res == -1 && Errno::last() == Errno::EBADF && is_opath(fd) {
+      let res = unsafe { libc::fchmodat(libc::AT_FDCWD, proc_self_fd(fd).as_ptr(), mode.bits(), 0) };
+      return Errno::result(res).map(drop);
+    }
+  }
+  Errno::result(res).map(drop)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::os::unix::fs::PermissionsExt;
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_fchmod_opath() {
+    use crate::open::{openat, OFlag, Symlink};
+    let tempdir = tempfile::tempdir().unwrap();
+    let file = tempdir.path().join("file");
+    std::fs::File::create(&file).unwrap();
+    let fd = openat(None, &file, OFlag::O_PATH, Mode::empty(), Symlink::Follow).unwrap();
+    fchmod(fd.as_raw(), Mode::from_bits_truncate(0o640)).unwrap();
+    assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o640);
+
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &link).unwrap();
+    let fd = openat(None, &link, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    let err = fchmod(fd.as_raw(), Mode::from_bits_truncate(0o600)).err().unwrap();
+    assert_eq!(err.as_errno(), Some(Errno::EOPNOTSUPP));
+    assert_eq!(std::fs::metadata(&file).unwrap().permissions().mode() & 0o7777, 0o640);
+  }
+}
@@ -2,11 +2,33 @@
 #![allow(dead_code)]
 
 
-use nix::{errno::Errno, /*Error, NixPath,*/ Result};
+use nix::{errno::Errno, /*Error,*/ NixPath, Result};
 use std::os::unix::io::RawFd;
 
 pub use nix::unistd::{Gid, Uid};
 
+use crate::fd::is_opath;
+use crate::open::Symlink;
+use nix::sys::stat::FileStat;
+use nix::unistd::{Group, User};
+use std::ffi::{CString, OsStr};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+// According to the POSIX specification, -1 is used to indicate that owner and group
+// are not to be changed.  Since uid_t and gid_t are unsigned types, we have to wrap
+// around to get -1.
+fn raw_ids(owner: Option<Uid>, group: Option<Gid>) -> (libc::uid_t, libc::gid_t) {
+  let uid: libc::uid_t = owner
+    .map(Into::into)
+    .unwrap_or_else(|| (0 as libc::uid_t).wrapping_sub(1));
+  let gid: libc::gid_t = group
+    .map(Into::into)
+    .unwrap_or_else(|| (0 as libc::gid_t).wrapping_sub(1));
+  (uid, gid)
+}
+
 /// Change the ownership of the file specified by a file descriptor to be owned
 /// by the specified `owner` (user) and `group` (see
 /// [fchown(2)](https://pubs.opengroup.org/onlinepubs/9699919799/functions/fchown.html).
@@ -14,21 +36,462 @@ pub use nix::unistd::{Gid, Uid};
 /// The owner/group for the provided path name will not be modified if `None` is
 /// provided for that argument.  Ownership change will be attempted for the fd
 /// only if `Some` owner/group is provided.
+///
+/// On Linux, `fd` may be an `O_PATH` descriptor (such as `Symlink::Open` gives), in which
+/// case the change is made with `fchownat` and `AT_EMPTY_PATH`; for a symlink, it's the
+/// link itself that changes owner.
 pub fn fchown(fd: RawFd, owner: Option<Uid>, group: Option<Gid>) -> Result<()> {
-  // According to the POSIX specification, -1 is used to indicate that owner and group
-  // are not to be changed.  Since uid_t and gid_t are unsigned types, we have to wrap
-  // around to get -1.
-  let uid: libc::uid_t = owner
-    .map(Into::into)
-    .unwrap_or_else(|| (0 as libc::uid_t).wrapping_sub(1));
-  let gid: libc::gid_t = group
-    .map(Into::into)
-    .unwrap_or_else(|| (0 as libc::gid_t).wrapping_sub(1));
-
+  let (uid, gid) = raw_ids(owner, group);
   let res = unsafe { libc::fchown(fd, uid, gid) };
+  #[cfg(target_os = "linux")]
+  {
+    if res == -1 && Errno::last() == Errno::EBADF && is_opath(fd) {
+      let empty = CString::new("").unwrap();
+      let res = unsafe { libc::fchownat(fd, empty.as_ptr(), uid, gid, libc::AT_EMPTY_PATH) };
+      return Errno::result(res).map(drop);
+    }
+  }
   Errno::result(res).map(drop)
 }
 
+/// What became of a file's setuid and setgid bits in `fchown_keep_special`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialBits {
+  /// There were none, or changing the owner didn't clear them.
+  Kept,
+  /// Changing the owner cleared them, and they were put back.
+  Restored,
+  /// Changing the owner cleared them, and putting them back failed (say, with EPERM
+  /// because the caller no longer owns the file).
+  Lost(nix::Error),
+}
+
+/// Like `fchown`, but puts back the setuid and setgid bits if changing the owner cleared them.
+///
+/// The kernel clears those bits when a regular file changes owner or group (even to the
+/// same ids), so that a file can't gain privileges that way. This reads the mode first,
+/// and if the bits are gone afterwards, sets the whole mode again with `fchmod`. Only a
+/// failure to change the ownership is an error; see `SpecialBits` for the rest.
+pub fn fchown_keep_special(fd: RawFd, owner: Option<Uid>, group: Option<Gid>) -> Result<SpecialBits> {
+  use crate::chmod::fchmod;
+  use crate::open::Mode;
+  use nix::sys::stat::fstat;
+  const SPECIAL: libc::mode_t = libc::S_ISUID | libc::S_ISGID;
+  let before = fstat(fd)?.st_mode;
+  fchown(fd, owner, group)?;
+  if before & SPECIAL == 0 || before & libc::S_IFMT != libc::S_IFREG {
+    return Ok(SpecialBits::Kept);
+  }
+  let res = fstat(fd).and_then(|st| {
+    if st.st_mode & SPECIAL == before & SPECIAL {
+      return Ok(SpecialBits::Kept);
+    }
+    fchmod(fd, Mode::from_bits_truncate(before & 0o7777)).map(|_| SpecialBits::Restored)
+  });
+  Ok(res.unwrap_or_else(SpecialBits::Lost))
+}
+
+/// Change the ownership of the file named by `path`, as `fchown` does for a file descriptor.
+///
+/// If `dirfd` has a value, then `path` is relative to directory associated with the file descriptor.
+///
+/// If `dirfd` is `None`, then `path` is relative to the current working directory.
+///
+/// If `links` is `Symlink::Open` and `path` names a symbolic link, then the link itself
+/// changes owner (as with `lchown`).
+///
+/// If `links` is `Symlink::Fail` and `path` names a symbolic link, this fails with ELOOP.
+/// The file is opened without following it before being checked, so a link can't be
+/// swapped in between. (Only available on Linux.)
+///
+/// # References
+///
+/// [fchownat(2)](https://pubs.opengroup.org/onlinepubs/9699919799/functions/fchownat.html)
+pub fn fchownat<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  owner: Option<Uid>,
+  group: Option<Gid>,
+  links: Symlink,
+) -> Result<()> {
+  let flag = match links {
+    Symlink::Follow => 0,
+    Symlink::Open => libc::AT_SYMLINK_NOFOLLOW,
+    Symlink::Fail => {
+      #[cfg(target_os = "linux")]
+      {
+        use crate::open::open_nofollow;
+        return fchown(open_nofollow(dirfd, path)?.as_raw(), owner, group);
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        use nix::Error;
+        return Err(Error::UnsupportedOperation);
+      }
+    }
+  };
+  let (uid, gid) = raw_ids(owner, group);
+  let res = path
+    .with_nix_path(|cstr| unsafe { libc::fchownat(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), uid, gid, flag) })?;
+  Errno::result(res).map(drop)
+}
+
+/// Why `parse_owner_spec` rejected a spec.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum OwnerSpecError {
+  /// No user has this name, and it isn't a number.
+  UnknownUser(String),
+  /// No group has this name, and it isn't a number.
+  UnknownGroup(String),
+  /// "user:" was given for a user id that has no passwd entry, so there's no login group to use.
+  NoLoginGroup(String),
+  /// Looking up a name failed.
+  Sys(nix::Error),
+}
+
+impl fmt::Display for OwnerSpecError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      OwnerSpecError::UnknownUser(name) => write!(f, "invalid user: {:?}", name),
+      OwnerSpecError::UnknownGroup(name) => write!(f, "invalid group: {:?}", name),
+      OwnerSpecError::NoLoginGroup(name) => write!(f, "no login group for user: {:?}", name),
+      OwnerSpecError::Sys(e) => e.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for OwnerSpecError {}
+
+impl From<nix::Error> for OwnerSpecError {
+  fn from(e: nix::Error) -> Self {
+    OwnerSpecError::Sys(e)
+  }
+}
+
+// A leading '+' means the id is numeric, and isn't to be looked up as a name. -1 isn't
+// accepted, since the kernel reads it as "leave unchanged".
+fn parse_id(name: &str) -> Option<u32> {
+  let digits = name.strip_prefix('+').unwrap_or(name);
+  if digits.is_empty() || !digits.bytes().all(|b| b.is_ascii_digit()) {
+    return None;
+  }
+  digits.parse().ok().filter(|id| *id != u32::MAX)
+}
+
+// from_name panics on an interior nul
+fn is_name(name: &str) -> bool {
+  !name.starts_with('+') && !name.contains('\0')
+}
+
+/// Parses an owner spec the way chown(1) does: "user", "user:group", "user:", ":group" or ":".
+///
+/// A name that isn't found is read as a numeric id, so ids without passwd or group
+/// entries are accepted; "+1001" is always numeric. "user:" gives the user's login group.
+/// The empty spec, like ":", changes nothing. `None` in the result means the owner or
+/// group isn't to change, as `fchown` and `fchownat` take it.
+pub fn parse_owner_spec(spec: &str) -> std::result::Result<(Option<Uid>, Option<Gid>), OwnerSpecError> {
+  let (user, group) = match spec.find(':') {
+    Some(colon) => (&spec[..colon], Some(&spec[colon + 1..])),
+    None => (spec, None),
+  };
+  let mut login_group = None;
+  let owner = if user.is_empty() {
+    None
+  } else {
+    let entry = if is_name(user) { User::from_name(user)? } else { None };
+    match entry {
+      Some(entry) => {
+        login_group = Some(entry.gid);
+        Some(entry.uid)
+      }
+      None => match parse_id(user) {
+        Some(uid) => Some(Uid::from_raw(uid)),
+        None => return Err(OwnerSpecError::UnknownUser(user.to_string())),
+      },
+    }
+  };
+  let group = match group {
+    None => None,
+    Some("") if owner.is_some() => match login_group {
+      Some(gid) => Some(gid),
+      // a numeric id may still have an entry
+      None => match User::from_uid(owner.unwrap())? {
+        Some(entry) => Some(entry.gid),
+        None => return Err(OwnerSpecError::NoLoginGroup(user.to_string())),
+      },
+    },
+    Some("") => None,
+    Some(name) => {
+      let entry = if is_name(name) { Group::from_name(name)? } else { None };
+      match entry {
+        Some(entry) => Some(entry.gid),
+        None => match parse_id(name) {
+          Some(gid) => Some(Gid::from_raw(gid)),
+          None => return Err(OwnerSpecError::UnknownGroup(name.to_string())),
+        },
+      }
+    }
+  };
+  Ok((owner, group))
+}
+
+/// The ownership of one file before and after `ChownOptions::chown` set it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OwnerChange {
+  pub path: PathBuf,
+  pub old: (Uid, Gid),
+  pub new: (Uid, Gid),
+}
+
+/// What `ChownOptions::chown` did.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ChownReport {
+  /// The files whose ownership was set, in the order that happened (a directory comes after
+  /// its contents). The old and new owners can be the same.
+  pub changes: Vec<OwnerChange>,
+  /// The files that couldn't be examined, listed or changed, and why.
+  pub errors: Vec<(PathBuf, nix::Error)>,
+}
+
+/// Options for changing the ownership of a whole tree, along the lines of `chown -R`.
+///
+/// The walk never descends through symlinks, and the files they name change only when
+/// `no_dereference` is unset. Each file is opened without following it (as with
+/// `Symlink::Open`), and that one descriptor is checked against `from` and then changed,
+/// so nothing can be swapped in between; a directory's contents are listed through it,
+/// and the directory is changed after them. The defaults are those of `chown -R`, except
+/// that `preserve_root` is set. (Only available on Linux and macos.)
+#[derive(Clone, Debug)]
+pub struct ChownOptions {
+  no_dereference: bool,
+  from_owner: Option<Uid>,
+  from_group: Option<Gid>,
+  preserve_root: bool,
+  one_file_system: bool,
+  continue_on_error: bool,
+}
+
+impl Default for ChownOptions {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl ChownOptions {
+  /// Creates options with `no_dereference`, `preserve_root` and `continue_on_error` set.
+  pub fn new() -> Self {
+    ChownOptions {
+      no_dereference: true,
+      from_owner: None,
+      from_group: None,
+      preserve_root: true,
+      one_file_system: false,
+      continue_on_error: true,
+    }
+  }
+
+  /// Changes symlinks themselves; if unset, the files they name change instead (`chown -h`).
+  pub fn no_dereference(&mut self, no_dereference: bool) -> &mut Self {
+    self.no_dereference = no_dereference;
+    self
+  }
+
+  /// Only changes files currently owned by `owner` and `group`, where those are `Some` (`chown --from`).
+  pub fn from(&mut self, owner: Option<Uid>, group: Option<Gid>) -> &mut Self {
+    self.from_owner = owner;
+    self.from_group = group;
+    self
+  }
+
+  /// Refuses, with EPERM, to descend into the root directory (`chown --preserve-root`).
+  pub fn preserve_root(&mut self, preserve_root: bool) -> &mut Self {
+    self.preserve_root = preserve_root;
+    self
+  }
+
+  /// Skips files on a different device from the starting point, along with anything under them.
+  pub fn one_file_system(&mut self, one_file_system: bool) -> &mut Self {
+    self.one_file_system = one_file_system;
+    self
+  }
+
+  /// Carries on after a failure; if unset, the walk stops at the first one.
+  pub fn continue_on_error(&mut self, continue_on_error: bool) -> &mut Self {
+    self.continue_on_error = continue_on_error;
+    self
+  }
+
+  /// Changes the owner and/or group of `path` and, if it's a directory, of everything
+  /// under it. `path` is relative to `dirfd`, or the current working directory if that's `None`.
+  ///
+  /// Failures for particular files are collected in the report, under paths that begin
+  /// with `path`. This only fails itself if the root directory can't be examined (for
+  /// `preserve_root`). A descriptor stays open for each directory level being walked.
+  pub fn chown<P: ?Sized + NixPath + AsRef<Path>>(
+    &self,
+    dirfd: Option<RawFd>,
+    path: &P,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+  ) -> Result<ChownReport> {
+    use nix::sys::stat::stat;
+    let root = if self.preserve_root {
+      let st = stat("/")?;
+      Some((st.st_dev, st.st_ino))
+    } else {
+      None
+    };
+    let mut walk = ChownWalk {
+      options: self,
+      owner,
+      group,
+      root,
+      dev: None,
+      report: ChownReport::default(),
+    };
+    walk_tree(
+      &mut walk,
+      dirfd.unwrap_or(libc::AT_FDCWD),
+      path,
+      path.as_ref().to_path_buf(),
+    );
+    Ok(walk.report)
+  }
+}
+
+/// What `walk_tree` does with the files it comes to.
+pub(crate) trait TreeWalk {
+  /// Whether to leave out the file that `st` describes, along with anything under it.
+  fn skip(&mut self, st: &FileStat) -> Result<bool>;
+
+  /// Handles the file `name` in `dirfd`, opened without following it as `fd`. A
+  /// directory is handled after its contents.
+  fn visit<N: ?Sized + NixPath>(&mut self, dirfd: RawFd, name: &N, fd: RawFd, st: &FileStat, path: &Path)
+    -> Result<()>;
+
+  /// Records a failure for `path`, and returns whether to carry on.
+  fn fail(&mut self, path: PathBuf, e: nix::Error) -> bool;
+}
+
+/// Walks `name` in `dirfd` and, if it's a directory, everything under it, never through
+/// symlinks. Each file is opened once, and everything is done through that descriptor,
+/// so nothing can be swapped in between. `path` names the file in failures. Returns
+/// false if `walk` stopped the walk.
+pub(crate) fn walk_tree<W: TreeWalk, N: ?Sized + NixPath>(walk: &mut W, dirfd: RawFd, name: &N, path: PathBuf) -> bool {
+  match try_walk_tree(walk, dirfd, name, &path) {
+    Ok(carry_on) => carry_on,
+    Err(e) => walk.fail(path, e),
+  }
+}
+
+fn try_walk_tree<W: TreeWalk, N: ?Sized + NixPath>(walk: &mut W, dirfd: RawFd, name: &N, path: &Path) -> Result<bool> {
+  use crate::open::{openat, Mode, OFlag};
+  use nix::dir::Dir;
+  use nix::sys::stat::fstat;
+  let fd = openat(Some(dirfd), name, OFlag::O_CLOEXEC, Mode::empty(), Symlink::Open)?;
+  let st = fstat(fd.as_raw())?;
+  if walk.skip(&st)? {
+    return Ok(true);
+  }
+  if st.st_mode & libc::S_IFMT == libc::S_IFDIR {
+    let mut dir = Dir::openat(fd.as_raw(), ".", OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty())?;
+    for entry in dir.iter() {
+      let entry = match entry {
+        Ok(entry) => entry,
+        Err(e) => {
+          if !walk.fail(path.to_path_buf(), e) {
+            return Ok(false);
+          }
+          break;
+        }
+      };
+      let child = entry.file_name();
+      let bytes = child.to_bytes();
+      if bytes == b"." || bytes == b".." {
+        continue;
+      }
+      if !walk_tree(walk, fd.as_raw(), child, path.join(OsStr::from_bytes(bytes))) {
+        return Ok(false);
+      }
+    }
+  }
+  walk.visit(dirfd, name, fd.as_raw(), &st, path)?;
+  Ok(true)
+}
+
+struct ChownWalk<'a> {
+  options: &'a ChownOptions,
+  owner: Option<Uid>,
+  group: Option<Gid>,
+  root: Option<(libc::dev_t, libc::ino_t)>,
+  // the device of the starting point
+  dev: Option<libc::dev_t>,
+  report: ChownReport,
+}
+
+impl TreeWalk for ChownWalk<'_> {
+  fn skip(&mut self, st: &FileStat) -> Result<bool> {
+    use nix::Error;
+    if self.skip_device(st.st_dev) {
+      return Ok(true);
+    }
+    if self.root == Some((st.st_dev, st.st_ino)) {
+      return Err(Error::Sys(Errno::EPERM));
+    }
+    Ok(false)
+  }
+
+  fn visit<N: ?Sized + NixPath>(
+    &mut self,
+    dirfd: RawFd,
+    name: &N,
+    fd: RawFd,
+    st: &FileStat,
+    path: &Path,
+  ) -> Result<()> {
+    use crate::open::{o_path, openat, Mode, OFlag};
+    use nix::sys::stat::fstat;
+    if st.st_mode & libc::S_IFMT == libc::S_IFLNK && !self.options.no_dereference {
+      let oflags = OFlag::from_bits_truncate(o_path()) | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC;
+      let target = openat(Some(dirfd), name, oflags, Mode::empty(), Symlink::Follow)?;
+      let st = fstat(target.as_raw())?;
+      return self.change(path, &st, |owner, group| fchown(target.as_raw(), owner, group));
+    }
+    self.change(path, st, |owner, group| fchown(fd, owner, group))
+  }
+
+  fn fail(&mut self, path: PathBuf, e: nix::Error) -> bool {
+    self.report.errors.push((path, e));
+    self.options.continue_on_error
+  }
+}
+
+impl ChownWalk<'_> {
+  fn skip_device(&mut self, dev: libc::dev_t) -> bool {
+    let start = *self.dev.get_or_insert(dev);
+    self.options.one_file_system && dev != start
+  }
+
+  fn change<F>(&mut self, path: &Path, st: &FileStat, chown: F) -> Result<()>
+  where
+    F: FnOnce(Option<Uid>, Option<Gid>) -> Result<()>,
+  {
+    let old = (Uid::from_raw(st.st_uid), Gid::from_raw(st.st_gid));
+    if matches!(self.options.from_owner, Some(uid) if uid != old.0)
+      || matches!(self.options.from_group, Some(gid) if gid != old.1)
+    {
+      return Ok(());
+    }
+    chown(self.owner, self.group)?;
+    self.report.changes.push(OwnerChange {
+      path: path.to_path_buf(),
+      old,
+      new: (self.owner.unwrap_or(old.0), self.group.unwrap_or(old.1)),
+    });
+    Ok(())
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -52,4 +515,203 @@ mod tests {
     fchown(fd, None, gid).unwrap();
     // std::fs::remove_file(&path).unwrap();
   }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_fchown_opath() {
+    use crate::open::{openat, Mode, OFlag, Symlink};
+    let uid = Some(nix::unistd::getuid());
+    let gid = Some(nix::unistd::getgid());
+
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("link");
+    std::os::unix::fs::symlink("missing", &path).unwrap();
+    let fd = openat(None, &path, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    fchown(fd.as_raw(), uid, gid).unwrap();
+    fchown(fd.as_raw(), None, gid).unwrap();
+  }
+
+  #[test]
+  fn test_fchownat() {
+    use nix::sys::stat::lstat;
+    let uid = nix::unistd::getuid();
+    let gid = nix::unistd::getgid();
+    let tempdir = tempfile::tempdir().unwrap();
+    let dirfd = File::open(tempdir.path()).unwrap();
+    let dirfd = Some(std::os::unix::io::AsRawFd::as_raw_fd(&dirfd));
+    File::create(tempdir.path().join("file")).unwrap();
+    std::os::unix::fs::symlink("file", tempdir.path().join("link")).unwrap();
+    for links in &[Symlink::Follow, Symlink::Open] {
+      fchownat(dirfd, "link", Some(uid), Some(gid), *links).unwrap();
+      fchownat(dirfd, "file", None, Some(gid), *links).unwrap();
+    }
+    #[cfg(target_os = "linux")]
+    {
+      fchownat(dirfd, "file", Some(uid), None, Symlink::Fail).unwrap();
+      let err = fchownat(dirfd, "link", Some(uid), None, Symlink::Fail).err().unwrap();
+      assert_eq!(err.as_errno(), Some(Errno::ELOOP));
+    }
+    if uid.is_root() {
+      let owner = |name| {
+        let st = lstat(&tempdir.path().join(name)).unwrap();
+        (st.st_uid, st.st_gid)
+      };
+      fchownat(dirfd, "link", Some(Uid::from_raw(1234)), None, Symlink::Open).unwrap();
+      assert_eq!(
+        (owner("link"), owner("file")),
+        ((1234, gid.as_raw()), (uid.as_raw(), gid.as_raw()))
+      );
+      fchownat(dirfd, "link", None, Some(Gid::from_raw(4321)), Symlink::Follow).unwrap();
+      assert_eq!(
+        (owner("link"), owner("file")),
+        ((1234, gid.as_raw()), (uid.as_raw(), 4321))
+      );
+    }
+  }
+
+  #[test]
+  fn test_parse_owner_spec() {
+    let root = (Some(Uid::from_raw(0)), Some(Gid::from_raw(0)));
+    assert_eq!(parse_owner_spec("root:root"), Ok(root));
+    assert_eq!(parse_owner_spec("root:"), Ok(root));
+    assert_eq!(parse_owner_spec("0:"), Ok(root));
+    assert_eq!(parse_owner_spec("root"), Ok((root.0, None)));
+    assert_eq!(parse_owner_spec(":root"), Ok((None, root.1)));
+    assert_eq!(parse_owner_spec(":"), Ok((None, None)));
+    assert_eq!(parse_owner_spec(""), Ok((None, None)));
+    let ids = (Some(Uid::from_raw(54321)), Some(Gid::from_raw(54322)));
+    assert_eq!(parse_owner_spec("54321:54322"), Ok(ids));
+    assert_eq!(parse_owner_spec("+54321:+54322"), Ok(ids));
+    assert_eq!(
+      parse_owner_spec("54321:"),
+      Err(OwnerSpecError::NoLoginGroup("54321".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec("no such user:"),
+      Err(OwnerSpecError::UnknownUser("no such user".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec("+root"),
+      Err(OwnerSpecError::UnknownUser("+root".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec("root:no\0such"),
+      Err(OwnerSpecError::UnknownGroup("no\0such".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec(":-1"),
+      Err(OwnerSpecError::UnknownGroup("-1".to_string()))
+    );
+    // the kernel would read these as -1
+    assert_eq!(
+      parse_owner_spec("4294967295:"),
+      Err(OwnerSpecError::UnknownUser("4294967295".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec(":+4294967295"),
+      Err(OwnerSpecError::UnknownGroup("+4294967295".to_string()))
+    );
+    assert_eq!(
+      parse_owner_spec("4294967294"),
+      Ok((Some(Uid::from_raw(4_294_967_294)), None))
+    );
+  }
+
+  #[test]
+  fn test_chown_options() {
+    use nix::sys::stat::lstat;
+    let uid = nix::unistd::getuid();
+    let gid = nix::unistd::getgid();
+    let tempdir = tempfile::tempdir().unwrap();
+    let top = tempdir.path().join("top");
+    std::fs::create_dir_all(top.join("sub")).unwrap();
+    File::create(top.join("sub").join("file")).unwrap();
+    File::create(tempdir.path().join("outside")).unwrap();
+    std::os::unix::fs::symlink("../../outside", top.join("sub").join("link")).unwrap();
+
+    let report = ChownOptions::new().chown(None, &top, Some(uid), None).unwrap();
+    assert_eq!(report.errors, vec![]);
+    let mut paths: Vec<_> = report.changes.iter().map(|change| change.path.clone()).collect();
+    assert_eq!(paths.pop(), Some(top.clone()));
+    assert_eq!(paths.pop(), Some(top.join("sub")));
+    paths.sort();
+    assert_eq!(paths, vec![top.join("sub").join("file"), top.join("sub").join("link")]);
+    assert!(report
+      .changes
+      .iter()
+      .all(|change| change.old == (uid, gid) && change.new == (uid, gid)));
+
+    // nothing matches
+    let stranger = Some(Uid::from_raw(54321));
+    let report = ChownOptions::new()
+      .from(stranger, None)
+      .chown(None, &top, Some(uid), None)
+      .unwrap();
+    assert_eq!(report, ChownReport::default());
+
+    let report = ChownOptions::new().chown(None, "/", None, None).unwrap();
+    assert_eq!(report.changes, vec![]);
+    assert_eq!(report.errors, vec![(PathBuf::from("/"), nix::Error::Sys(Errno::EPERM))]);
+
+    let missing = tempdir.path().join("missing");
+    let report = ChownOptions::new().chown(None, &missing, Some(uid), None).unwrap();
+    assert_eq!(report.errors, vec![(missing, nix::Error::Sys(Errno::ENOENT))]);
+
+    if uid.is_root() {
+      let owner = |path: &Path| {
+        let st = lstat(path).unwrap();
+        (Uid::from_raw(st.st_uid), Gid::from_raw(st.st_gid))
+      };
+      let (other_uid, other_gid) = (Uid::from_raw(1234), Gid::from_raw(4321));
+      let report = ChownOptions::new()
+        .no_dereference(false)
+        .chown(None, &top, Some(other_uid), Some(other_gid))
+        .unwrap();
+      assert_eq!(report.errors, vec![]);
+      assert_eq!(owner(&top.join("sub").join("file")), (other_uid, other_gid));
+      assert_eq!(owner(&top.join("sub").join("link")), (uid, gid));
+      assert_eq!(owner(&tempdir.path().join("outside")), (other_uid, other_gid));
+      assert_eq!(report.changes.last().unwrap().old, (uid, gid));
+
+      let report = ChownOptions::new()
+        .from(None, Some(other_gid))
+        .chown(None, &top.join("sub"), None, Some(gid))
+        .unwrap();
+      assert_eq!(report.changes.len(), 2);
+      assert_eq!(owner(&top.join("sub")), (other_uid, gid));
+      assert_eq!(owner(&top.join("sub").join("link")), (uid, gid));
+      assert_eq!(owner(&top), (other_uid, other_gid));
+    }
+  }
+
+  #[test]
+  fn test_fchown_keep_special() {
+    use nix::sys::stat::fstat;
+    use std::os::unix::fs::PermissionsExt;
+    use std::os::unix::io::AsRawFd;
+    let uid = Some(nix::unistd::getuid());
+    let gid = Some(nix::unistd::getgid());
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("helper");
+    let file = File::create(&path).unwrap();
+    let fd = file.as_raw_fd();
+    let mode = |fd| fstat(fd).unwrap().st_mode & 0o7777;
+
+    file.set_permissions(std::fs::Permissions::from_mode(0o6755)).unwrap();
+    fchown(fd, uid, gid).unwrap();
+    assert_eq!(mode(fd), 0o755);
+
+    file.set_permissions(std::fs::Permissions::from_mode(0o6755)).unwrap();
+    assert_eq!(fchown_keep_special(fd, uid, gid), Ok(SpecialBits::Restored));
+    assert_eq!(mode(fd), 0o6755);
+
+    file.set_permissions(std::fs::Permissions::from_mode(0o644)).unwrap();
+    assert_eq!(fchown_keep_special(fd, uid, gid), Ok(SpecialBits::Kept));
+    assert_eq!(mode(fd), 0o644);
+
+    let dir = File::open(tempdir.path()).unwrap();
+    dir.set_permissions(std::fs::Permissions::from_mode(0o2755)).unwrap();
+    assert_eq!(fchown_keep_special(dir.as_raw_fd(), uid, gid), Ok(SpecialBits::Kept));
+    assert_eq!(mode(dir.as_raw_fd()), 0o2755);
+  }
 }
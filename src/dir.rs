@@ -0,0 +1,494 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, NixPath, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::access::{faccessat, AccessFlags};
+use crate::chmod::fchmod;
+use crate::chown::{fchown, Gid, Uid};
+use crate::fd::OwnedFd;
+use crate::mkdir::mkdirat;
+use crate::open::{openat, openat_resolve, readlinkat_bytes, Mode, OFlag, ResolveFlag, Symlink};
+#[cfg(not(target_env = "musl"))]
+use crate::stat::{fstat, fstatat, NodeEntry};
+use crate::time::{futime, utimeat, TimeLike};
+use nix::dir::Dir;
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStringExt;
+use std::path::PathBuf;
+
+/// An open directory, with the crate's *at functions available as methods relative to it.
+///
+/// A handle made `confine`d only gives access to what's beneath its directory: absolute
+/// paths and paths whose ".." components would climb out of it fail with EXDEV (as they
+/// do for `openat2` with `RESOLVE_BENEATH`), and so do symlinks that lead outside.
+/// Handing a confined `DirHandle` to some code limits it to that part of the tree.
+#[derive(Debug)]
+pub struct DirHandle {
+  fd: OwnedFd,
+  confined: bool,
+}
+
+// Checks that `path` doesn't lexically escape the directory it's relative to.
+fn check_beneath(path: &[u8]) -> Result<()> {
+  if path.first() == Some(&b'/') {
+    return Err(Error::Sys(Errno::EXDEV));
+  }
+  let mut depth = 0usize;
+  for comp in path.split(|c| *c == b'/') {
+    match comp {
+      b"" | b"." => (),
+      b".." if depth == 0 => return Err(Error::Sys(Errno::EXDEV)),
+      b".." => depth -= 1,
+      _ => depth += 1,
+    }
+  }
+  Ok(())
+}
+
+// Splits `path` into the directory part and the final component. A final "." or ".."
+// is kept with the directory part, so that the final component never leads upwards.
+fn split_last(path: &[u8]) -> (&[u8], &[u8]) {
+  let mut end = path.len();
+  while end > 1 && path[end - 1] == b'/' {
+    end -= 1;
+  }
+  let path = &path[..end];
+  let (parent, last) = match path.iter().rposition(|c| *c == b'/') {
+    Some(pos) => (&path[..pos], &path[pos + 1..]),
+    None => (&b""[..], path),
+  };
+  if last == b"." || last == b".." {
+    (path, b".")
+  } else {
+    (parent, last)
+  }
+}
+
+fn o_path() -> OFlag {
+  #[cfg(target_os = "linux")]
+  {
+    OFlag::O_PATH
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    OFlag::O_RDONLY
+  }
+}
+
+impl DirHandle {
+  /// Opens the directory `path`.
+  ///
+  /// If `dirfd` has a value, then `path` is relative to directory associated with the file descriptor.
+  ///
+  /// If `dirfd` is `None`, then `path` is relative to the current working directory.
+  pub fn new<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P) -> Result<DirHandle> {
+    let fd = openat(
+      dirfd,
+      path,
+      OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+      Mode::empty(),
+      Symlink::Follow,
+    )?;
+    Ok(DirHandle { fd, confined: false })
+  }
+
+  /// Takes ownership of `fd`, which should refer to a directory.
+  pub fn from_fd(fd: OwnedFd) -> DirHandle {
+    DirHandle { fd, confined: false }
+  }
+
+  /// Confines the handle to its directory; see the type's description.
+  pub fn confine(self) -> DirHandle {
+    DirHandle { confined: true, ..self }
+  }
+
+  #[inline]
+  pub fn is_confined(&self) -> bool {
+    self.confined
+  }
+
+  #[inline]
+  pub fn as_raw(&self) -> RawFd {
+    self.fd.as_raw()
+  }
+
+  // Calls `f` with a directory and a name in it that together refer to `path`. For a
+  // confined handle, the directory is resolved beneath ours, and the name is a single
+  // component that doesn't lead upwards.
+  fn at<P, F, R>(&self, path: &P, f: F) -> Result<R>
+  where
+    P: ?Sized + NixPath,
+    F: FnOnce(RawFd, &[u8]) -> Result<R>,
+  {
+    path
+      .with_nix_path(|cstr| {
+        let path = cstr.to_bytes();
+        if !self.confined {
+          return f(self.fd.as_raw(), path);
+        }
+        check_beneath(path)?;
+        match split_last(path) {
+          (b"", last) => f(self.fd.as_raw(), last),
+          (parent, last) => {
+            let oflags = o_path() | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC;
+            let parent = openat_resolve(
+              Some(self.fd.as_raw()),
+              parent,
+              oflags,
+              Mode::empty(),
+              ResolveFlag::RESOLVE_BENEATH,
+            )?;
+            f(parent.as_raw(), last)
+          }
+        }
+      })
+      .and_then(|ok| ok)
+  }
+
+  // Opens `path` itself (on Linux with `O_PATH`, so this works whatever its permissions), for
+  // the operations that act on a descriptor. `Symlink::Fail` gives ELOOP for a symlink.
+  fn node<P: ?Sized + NixPath>(&self, path: &P, links: Symlink) -> Result<OwnedFd> {
+    let oflags = o_path() | OFlag::O_CLOEXEC;
+    let fd = match links {
+      Symlink::Follow if self.confined => path
+        .with_nix_path(|cstr| {
+          check_beneath(cstr.to_bytes())?;
+          openat_resolve(
+            Some(self.fd.as_raw()),
+            cstr,
+            oflags,
+            Mode::empty(),
+            ResolveFlag::RESOLVE_BENEATH,
+          )
+        })
+        .and_then(|ok| ok)?,
+      Symlink::Follow => openat(Some(self.fd.as_raw()), path, oflags, Mode::empty(), links)?,
+      _ => self.at(path, |dirfd, name| {
+        openat(Some(dirfd), name, oflags, Mode::empty(), Symlink::Open)
+      })?,
+    };
+    if links == Symlink::Fail && (nix::sys::stat::fstat(fd.as_raw())?.st_mode & libc::S_IFMT) == libc::S_IFLNK {
+      return Err(Error::Sys(Errno::ELOOP));
+    }
+    Ok(fd)
+  }
+
+  /// Opens the file named by `path`; see `openat`.
+  pub fn open<P: ?Sized + NixPath>(&self, path: &P, oflags: OFlag, mode: Mode, links: Symlink) -> Result<OwnedFd> {
+    if self.confined && links == Symlink::Follow {
+      path
+        .with_nix_path(|cstr| {
+          check_beneath(cstr.to_bytes())?;
+          openat_resolve(Some(self.fd.as_raw()), cstr, oflags, mode, ResolveFlag::RESOLVE_BENEATH)
+        })
+        .and_then(|ok| ok)
+    } else {
+      self.at(path, |dirfd, name| openat(Some(dirfd), name, oflags, mode, links))
+    }
+  }
+
+  /// Opens the subdirectory `path` as another handle, which is confined if this one is.
+  pub fn open_dir<P: ?Sized + NixPath>(&self, path: &P) -> Result<DirHandle> {
+    let fd = self.open(
+      path,
+      OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+      Mode::empty(),
+      Symlink::Follow,
+    )?;
+    Ok(DirHandle {
+      fd,
+      confined: self.confined,
+    })
+  }
+
+  /// Queries the file named by `path`; see `fstatat`.
+  #[cfg(not(target_env = "musl"))]
+  pub fn stat<P: ?Sized + NixPath>(&self, path: &P, links: Symlink) -> Result<NodeEntry> {
+    if self.confined {
+      fstat(self.node(path, links)?.as_raw())
+    } else {
+      fstatat(Some(self.fd.as_raw()), path, links)
+    }
+  }
+
+  /// Checks the file named by `path` for accessibility; see `faccessat`.
+  ///
+  /// For a confined handle, following a final symlink relies on /proc, so isn't supported off Linux.
+  pub fn access<P: ?Sized + NixPath>(&self, path: &P, mode: AccessFlags, links: Symlink) -> Result<()> {
+    if self.confined && links == Symlink::Follow {
+      #[cfg(target_os = "linux")]
+      {
+        let fd = self.node(path, links)?;
+        faccessat(
+          None,
+          crate::fd::proc_self_fd(fd.as_raw()).as_c_str(),
+          mode,
+          Symlink::Follow,
+        )
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        let _ = (path, mode);
+        Err(Error::UnsupportedOperation)
+      }
+    } else {
+      self.at(path, |dirfd, name| faccessat(Some(dirfd), name, mode, links))
+    }
+  }
+
+  /// Creates the directory `path`, and with `recursive` any missing parents; see `mkdirat`.
+  pub fn mkdir<P: ?Sized + NixPath>(&self, path: &P, mode: Mode, recursive: bool) -> Result<()> {
+    if !(self.confined && recursive) {
+      return self.at(path, |dirfd, name| mkdirat(Some(dirfd), name, mode, recursive));
+    }
+    // make each level in turn, so that every step is resolved beneath the handle
+    path
+      .with_nix_path(|cstr| {
+        let path = cstr.to_bytes();
+        check_beneath(path)?;
+        let mut end = 0;
+        while end < path.len() {
+          end = match path[end + 1..].iter().position(|c| *c == b'/') {
+            Some(pos) => end + 1 + pos,
+            None => path.len(),
+          };
+          match self.at(&path[..end], |dirfd, name| mkdirat(Some(dirfd), name, mode, false)) {
+            Err(Error::Sys(Errno::EEXIST)) if end < path.len() => (),
+            res => res?,
+          }
+        }
+        Ok(())
+      })
+      .and_then(|ok| ok)
+  }
+
+  /// Creates a symlink at `linkpath` that contains `target`.
+  ///
+  /// `target` isn't checked, even for a confined handle, since it's only followed later;
+  /// opening it through a confined handle will keep to the handle's directory.
+  pub fn symlink<P1: ?Sized + NixPath, P2: ?Sized + NixPath>(&self, target: &P1, linkpath: &P2) -> Result<()> {
+    self.at(linkpath, |dirfd, name| {
+      nix::unistd::symlinkat(target, Some(dirfd), name)
+    })
+  }
+
+  /// Reads the target of the symlink named by `path`.
+  pub fn readlink<P: ?Sized + NixPath>(&self, path: &P) -> Result<PathBuf> {
+    let target = self.at(path, readlinkat_bytes)?;
+    Ok(PathBuf::from(OsString::from_vec(target)))
+  }
+
+  /// Renames `from` to `to`, both relative to the handle.
+  pub fn rename<P1: ?Sized + NixPath, P2: ?Sized + NixPath>(&self, from: &P1, to: &P2) -> Result<()> {
+    self.at(from, |from_dirfd, from_name| {
+      self.at(to, |to_dirfd, to_name| {
+        nix::fcntl::renameat(Some(from_dirfd), from_name, Some(to_dirfd), to_name)
+      })
+    })
+  }
+
+  /// Removes the name `path`, which mustn't be a directory.
+  pub fn unlink<P: ?Sized + NixPath>(&self, path: &P) -> Result<()> {
+    self.at(path, |dirfd, name| {
+      nix::unistd::unlinkat(Some(dirfd), name, nix::unistd::UnlinkatFlags::NoRemoveDir)
+    })
+  }
+
+  /// Removes the empty directory `path`.
+  pub fn rmdir<P: ?Sized + NixPath>(&self, path: &P) -> Result<()> {
+    self.at(path, |dirfd, name| {
+      nix::unistd::unlinkat(Some(dirfd), name, nix::unistd::UnlinkatFlags::RemoveDir)
+    })
+  }
+
+  /// Changes the access and modification times of the file named by `path`; see `utimeat`.
+  pub fn set_times<P: ?Sized + NixPath, Ta: TimeLike, Tm: TimeLike>(
+    &self,
+    path: &P,
+    atime: &Ta,
+    mtime: &Tm,
+    links: Symlink,
+  ) -> Result<()> {
+    if self.confined {
+      futime(self.node(path, links)?.as_raw(), atime, mtime)
+    } else {
+      utimeat(Some(self.fd.as_raw()), path, atime, mtime, links)
+    }
+  }
+
+  /// Changes the owner and/or group of the file named by `path`; see `fchown`.
+  pub fn chown<P: ?Sized + NixPath>(
+    &self,
+    path: &P,
+    owner: Option<Uid>,
+    group: Option<Gid>,
+    links: Symlink,
+  ) -> Result<()> {
+    fchown(self.node(path, links)?.as_raw(), owner, group)
+  }
+
+  /// Changes the permission bits of the file named by `path`; see `fchmod`.
+  ///
+  /// With `Symlink::Open`, this fails with EOPNOTSUPP if `path` names a symlink.
+  pub fn chmod<P: ?Sized + NixPath>(&self, path: &P, mode: Mode, links: Symlink) -> Result<()> {
+    fchmod(self.node(path, links)?.as_raw(), mode)
+  }
+
+  /// Lists the handle's directory, from the beginning each time this is called.
+  pub fn iterate(&self) -> Result<Dir> {
+    let fd = openat(
+      Some(self.fd.as_raw()),
+      ".",
+      OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+      Mode::empty(),
+      Symlink::Follow,
+    )?;
+    Dir::from_fd(fd.into_raw())
+  }
+}
+
+impl AsRawFd for DirHandle {
+  #[inline]
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd.as_raw()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::time::Duration;
+
+  fn errno<T>(res: Result<T>) -> Option<Errno> {
+    res.err().and_then(|e| e.as_errno())
+  }
+
+  #[test]
+  fn test_dir_handle() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = DirHandle::new(None, tempdir.path()).unwrap();
+    dir.mkdir("a/b", Mode::from_bits_truncate(0o755), true).unwrap();
+    let fd = dir
+      .open(
+        "a/file",
+        OFlag::O_CREAT | OFlag::O_WRONLY,
+        Mode::from_bits_truncate(0o644),
+        Symlink::Follow,
+      )
+      .unwrap();
+    drop(fd);
+    dir.symlink("file", "a/link").unwrap();
+    assert_eq!(dir.readlink("a/link").unwrap(), PathBuf::from("file"));
+    dir
+      .chmod("a/link", Mode::from_bits_truncate(0o600), Symlink::Follow)
+      .unwrap();
+    dir
+      .set_times(
+        "a/file",
+        &Duration::from_secs(1000),
+        &Duration::from_secs(2000),
+        Symlink::Follow,
+      )
+      .unwrap();
+    #[cfg(not(target_env = "musl"))]
+    {
+      let entry = dir.stat("a/link", Symlink::Follow).unwrap();
+      assert_eq!(entry.st_mode & 0o7777, 0o600);
+      assert_eq!(entry.st_mtime, 2000);
+    }
+    dir.access("a/file", AccessFlags::R_OK, Symlink::Follow).unwrap();
+    dir.rename("a/file", "a/b/moved").unwrap();
+    assert_eq!(
+      errno(dir.access("a/link", AccessFlags::F_OK, Symlink::Follow)),
+      Some(Errno::ENOENT)
+    );
+    dir.unlink("a/link").unwrap();
+    assert_eq!(errno(dir.rmdir("a/b")), Some(Errno::ENOTEMPTY));
+    dir.unlink("a/b/moved").unwrap();
+    dir.rmdir("a/b").unwrap();
+
+    let mut names: Vec<_> = dir
+      .iterate()
+      .unwrap()
+      .iter()
+      .map(|e| e.unwrap().file_name().to_owned())
+      .collect();
+    names.sort();
+    let expected: Vec<_> = [".", "..", "a"]
+      .iter()
+      .map(|s| std::ffi::CString::new(*s).unwrap())
+      .collect();
+    assert_eq!(names, expected);
+  }
+
+  #[test]
+  fn test_dir_handle_confined() {
+    let tempdir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(tempdir.path().join("root")).unwrap();
+    std::fs::write(tempdir.path().join("secret"), b"").unwrap();
+    let dir = DirHandle::new(None, &tempdir.path().join("root")).unwrap().confine();
+    assert!(dir.is_confined());
+
+    dir.mkdir("a/b", Mode::from_bits_truncate(0o755), true).unwrap();
+    dir.symlink("../../secret", "a/up").unwrap();
+    dir.symlink("/etc", "a/abs").unwrap();
+    dir.symlink("b", "a/down").unwrap();
+    let rdonly = OFlag::O_RDONLY;
+    assert_eq!(
+      errno(dir.open("/etc/passwd", rdonly, Mode::empty(), Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(
+      errno(dir.open("a/../../secret", rdonly, Mode::empty(), Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(
+      errno(dir.open("a/up", rdonly, Mode::empty(), Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(
+      errno(dir.open("a/abs/x", rdonly, Mode::empty(), Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(
+      errno(dir.chmod("a/up", Mode::empty(), Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(errno(dir.mkdir("a/abs/x", Mode::empty(), false)), Some(Errno::EXDEV));
+    assert_eq!(errno(dir.unlink("..")), Some(Errno::EXDEV));
+    assert_eq!(errno(dir.chown("a/up", None, None, Symlink::Fail)), Some(Errno::ELOOP));
+    dir.chown("a/up", None, None, Symlink::Open).unwrap();
+
+    // symlinks that stay inside are fine
+    dir
+      .open("a/down", rdonly | OFlag::O_DIRECTORY, Mode::empty(), Symlink::Follow)
+      .unwrap();
+    dir.mkdir("a/down/c", Mode::from_bits_truncate(0o755), false).unwrap();
+    dir.access("a/down/c", AccessFlags::F_OK, Symlink::Follow).unwrap();
+    dir
+      .set_times(
+        "a/down/c",
+        &Duration::from_secs(1000),
+        &Duration::from_secs(2000),
+        Symlink::Follow,
+      )
+      .unwrap();
+    assert_eq!(
+      std::fs::metadata(tempdir.path().join("root/a/b/c"))
+        .unwrap()
+        .modified()
+        .unwrap(),
+      std::time::UNIX_EPOCH + Duration::from_secs(2000)
+    );
+
+    let sub = dir.open_dir("a").unwrap();
+    assert!(sub.is_confined());
+    assert_eq!(
+      errno(sub.access("../a", AccessFlags::F_OK, Symlink::Follow)),
+      Some(Errno::EXDEV)
+    );
+    sub.access("b/../down", AccessFlags::F_OK, Symlink::Open).unwrap();
+  }
+}
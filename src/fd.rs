@@ -0,0 +1,168 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Result};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+use std::marker::PhantomData;
+use std::mem;
+
+/// An owned file descriptor, which is closed when dropped.
+///
+/// Use `close` instead of dropping to find out whether closing failed.
+#[derive(Debug, PartialEq, Eq, Hash)]
+pub struct OwnedFd {
+  fd: RawFd,
+}
+
+/// A file descriptor borrowed from an `OwnedFd`, which can't outlive it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct BorrowedFd<'a> {
+  fd: RawFd,
+  owner: PhantomData<&'a OwnedFd>,
+}
+
+impl OwnedFd {
+  /// Takes ownership of `fd`, which will be closed when the result is dropped.
+  ///
+  /// # Safety
+  ///
+  /// `fd` must be an open file descriptor that nothing else will close.
+  #[inline]
+  pub unsafe fn from_raw(fd: RawFd) -> Self {
+    OwnedFd { fd }
+  }
+
+  #[inline]
+  pub fn as_raw(&self) -> RawFd {
+    self.fd
+  }
+
+  /// Gives up ownership of the descriptor without closing it.
+  #[inline]
+  pub fn into_raw(self) -> RawFd {
+    let fd = self.fd;
+    mem::forget(self);
+    fd
+  }
+
+  #[inline]
+  pub fn borrow(&self) -> BorrowedFd<'_> {
+    BorrowedFd {
+      fd: self.fd,
+      owner: PhantomData,
+    }
+  }
+
+  /// Duplicates the descriptor, with close-on-exec set on the copy.
+  pub fn try_clone(&self) -> Result<OwnedFd> {
+    let res = unsafe { libc::fcntl(self.fd, libc::F_DUPFD_CLOEXEC, 0) };
+    Errno::result(res).map(|fd| OwnedFd { fd })
+  }
+
+  /// Closes the descriptor, reporting any error from close(2).
+  pub fn close(self) -> Result<()> {
+    let res = unsafe { libc::close(self.into_raw()) };
+    Errno::result(res).map(drop)
+  }
+}
+
+impl Drop for OwnedFd {
+  fn drop(&mut self) {
+    // errors are ignored here; use `close` to see them
+    unsafe { libc::close(self.fd) };
+  }
+}
+
+impl AsRawFd for OwnedFd {
+  #[inline]
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for OwnedFd {
+  #[inline]
+  fn into_raw_fd(self) -> RawFd {
+    self.into_raw()
+  }
+}
+
+impl FromRawFd for OwnedFd {
+  #[inline]
+  unsafe fn from_raw_fd(fd: RawFd) -> Self {
+    Self::from_raw(fd)
+  }
+}
+
+impl From<OwnedFd> for std::fs::File {
+  fn from(fd: OwnedFd) -> Self {
+    unsafe { std::fs::File::from_raw_fd(fd.into_raw()) }
+  }
+}
+
+impl From<std::fs::File> for OwnedFd {
+  fn from(file: std::fs::File) -> Self {
+    unsafe { OwnedFd::from_raw(file.into_raw_fd()) }
+  }
+}
+
+impl<'a> BorrowedFd<'a> {
+  #[inline]
+  pub fn as_raw(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl<'a> AsRawFd for BorrowedFd<'a> {
+  #[inline]
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+/// Reports whether `fd` was opened with `O_PATH` (never the case off Linux).
+pub(crate) fn is_opath(fd: RawFd) -> bool {
+  #[cfg(target_os = "linux")]
+  {
+    let res = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    res != -1 && (res & libc::O_PATH) != 0
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    let _ = fd;
+    false
+  }
+}
+
+/// The /proc magic link that refers to whatever `fd` refers to.
+pub(crate) fn proc_self_fd(fd: RawFd) -> std::ffi::CString {
+  std::ffi::CString::new(format!("/proc/self/fd/{}", fd)).unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use nix::fcntl::{fcntl, FcntlArg};
+
+  #[test]
+  fn test_owned_fd() {
+    let file = tempfile::tempfile().unwrap();
+    let fd = OwnedFd::from(file);
+    let raw = fd.as_raw();
+    assert_eq!(fd.borrow().as_raw_fd(), raw);
+
+    let copy = fd.try_clone().unwrap();
+    assert_ne!(copy.as_raw(), raw);
+    let flags = fcntl(copy.as_raw(), FcntlArg::F_GETFD).unwrap();
+    assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    drop(copy);
+
+    let raw2 = fd.into_raw();
+    assert_eq!(raw2, raw);
+    let fd = unsafe { OwnedFd::from_raw(raw2) };
+    // the descriptor survived into_raw and the drop of its duplicate
+    assert!(fcntl(fd.as_raw(), FcntlArg::F_GETFD).is_ok());
+    fd.close().unwrap();
+  }
+}
@@ -0,0 +1,484 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, Result};
+use std::os::unix::io::RawFd;
+
+use crate::access::{access_mode, classify_target, get_path, get_status, names_file, AccessMode, FdTarget};
+use crate::bytes::{get_words, read_all, strip_newline, trim_start};
+#[cfg(not(target_env = "musl"))]
+use crate::open::Symlink;
+use crate::open::{readlinkat_bytes, OFlag};
+#[cfg(not(target_env = "musl"))]
+use crate::stat::{fstat, fstatat, NodeEntry};
+use nix::dir::Dir;
+use nix::sys::stat::Mode;
+use nix::unistd::Pid;
+use std::ffi::CStr;
+use std::os::unix::io::AsRawFd;
+
+/*
+ * The formats are those printed by the kernel's fdinfo handlers:
+ *   pos:\t%lli  flags:\t0%o  mnt_id:\t%i  ino:\t%lu
+ *   eventfd-count: %16llx
+ *   tfd: %8d events: %8x data: %16llx  pos:%lli ino:%lx sdev:%x
+ *   inotify wd:%x ino:%lx sdev:%x mask:%x ignored_mask:%x ...
+ *   clockid: %d  ticks: %llu  settime flags: 0%o  it_value: (%llu, %llu)  it_interval: (%llu, %llu)
+ *   lock:\t<a line in the format of /proc/locks>
+ */
+
+/// An inotify watch, from an inotify descriptor's fdinfo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InotifyWatch {
+  pub wd: i32,
+  /// The watched inode, and the device it's on (in the kernel's encoding).
+  pub ino: u64,
+  pub sdev: u64,
+  pub mask: u32,
+  pub ignored_mask: u32,
+}
+
+/// A descriptor registered with an epoll instance, from its fdinfo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EpollTarget {
+  pub tfd: RawFd,
+  pub events: u32,
+  pub data: u64,
+  pub pos: i64,
+  pub ino: u64,
+  pub sdev: u64,
+}
+
+/// The settings of a timerfd, from its fdinfo.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimerfdInfo {
+  pub clockid: libc::clockid_t,
+  pub ticks: u64,
+  pub settime_flags: i32,
+  /// Time until the next expiry, and the interval, as (seconds, nanoseconds).
+  pub it_value: (u64, u64),
+  pub it_interval: (u64, u64),
+}
+
+/// A lock held through the descriptor, in the terms of /proc/locks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+  /// "FLOCK", "POSIX", "OFDLCK", "LEASE" and so on.
+  pub kind: String,
+  /// "ADVISORY" or "MANDATORY", or for leases "ACTIVE", "BREAKING" and so on.
+  pub mode: String,
+  /// "READ", "WRITE" or "UNLCK".
+  pub access: String,
+  /// -1 for open file description locks.
+  pub pid: i32,
+  pub major: u32,
+  pub minor: u32,
+  pub ino: u64,
+  pub start: u64,
+  /// `None` for a lock that extends to the end of the file.
+  pub end: Option<u64>,
+}
+
+/// The contents of /proc/<pid>/fdinfo/<fd>.
+///
+/// Sections that don't apply to the kind of file that's open are empty or `None`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FdInfo {
+  pub pos: i64,
+  pub flags: OFlag,
+  /// Not reported before Linux 3.15.
+  pub mnt_id: Option<u64>,
+  /// Not reported before Linux 5.14.
+  pub ino: Option<u64>,
+  pub eventfd_count: Option<u64>,
+  pub timerfd: Option<TimerfdInfo>,
+  pub epoll: Vec<EpollTarget>,
+  pub inotify: Vec<InotifyWatch>,
+  pub locks: Vec<LockInfo>,
+}
+
+fn invalid<T>() -> Result<T> {
+  Err(Error::Sys(Errno::EINVAL))
+}
+
+fn parse_num<T: std::str::FromStr>(value: &[u8]) -> Result<T> {
+  std::str::from_utf8(value)
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .map_or_else(invalid, Ok)
+}
+
+fn parse_radix(value: &[u8], radix: u32) -> Result<u64> {
+  std::str::from_utf8(value)
+    .ok()
+    .and_then(|s| u64::from_str_radix(s, radix).ok())
+    .map_or_else(invalid, Ok)
+}
+
+// Finds the value of "key:value" among the words of `line`.
+fn field<'a>(line: &'a [u8], key: &[u8]) -> Result<&'a [u8]> {
+  line
+    .split(|b| b.is_ascii_whitespace())
+    .find_map(|word| {
+      if word.len() > key.len() && word.starts_with(key) && word[key.len()] == b':' {
+        Some(&word[key.len() + 1..])
+      } else {
+        None
+      }
+    })
+    .map_or_else(invalid, Ok)
+}
+
+// Parses "(%llu, %llu)".
+fn parse_pair(value: &[u8]) -> Result<(u64, u64)> {
+  let value = trim_start(value);
+  if !value.starts_with(b"(") || !value.ends_with(b")") {
+    return invalid();
+  }
+  let mut parts = value[1..value.len() - 1].split(|b| *b == b',');
+  match (parts.next(), parts.next(), parts.next()) {
+    (Some(a), Some(b), None) => Ok((parse_num(trim_start(a))?, parse_num(trim_start(b))?)),
+    _ => invalid(),
+  }
+}
+
+fn parse_inotify(line: &[u8]) -> Result<InotifyWatch> {
+  Ok(InotifyWatch {
+    wd: parse_radix(field(line, b"wd")?, 16)? as i32,
+    ino: parse_radix(field(line, b"ino")?, 16)?,
+    sdev: parse_radix(field(line, b"sdev")?, 16)?,
+    mask: parse_radix(field(line, b"mask")?, 16)? as u32,
+    ignored_mask: parse_radix(field(line, b"ignored_mask")?, 16)? as u32,
+  })
+}
+
+fn parse_epoll(value: &[u8]) -> Result<EpollTarget> {
+  // the first three have a space after the colon: "tfd: 5 events: 19 data: 5  pos:0 ..."
+  let words = get_words(value, &[2, 4, 6]).map_or_else(invalid, Ok)?;
+  Ok(EpollTarget {
+    tfd: parse_num(words[0])?,
+    events: parse_radix(words[1], 16)? as u32,
+    data: parse_radix(words[2], 16)?,
+    pos: parse_num(field(value, b"pos")?)?,
+    ino: parse_radix(field(value, b"ino")?, 16)?,
+    sdev: parse_radix(field(value, b"sdev")?, 16)?,
+  })
+}
+
+fn parse_lock(value: &[u8]) -> Result<LockInfo> {
+  // "1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF"
+  let words = get_words(value, &[2, 3, 4, 5, 6, 7, 8]).map_or_else(invalid, Ok)?;
+  let text = |word: &[u8]| String::from_utf8_lossy(word).into_owned();
+  let mut dev = words[4].split(|b| *b == b':');
+  let (major, minor, ino) = match (dev.next(), dev.next(), dev.next(), dev.next()) {
+    (Some(major), Some(minor), Some(ino), None) => (
+      parse_radix(major, 16)? as u32,
+      parse_radix(minor, 16)? as u32,
+      parse_num(ino)?,
+    ),
+    _ => return invalid(),
+  };
+  Ok(LockInfo {
+    kind: text(words[0]),
+    mode: text(words[1]),
+    access: text(words[2]),
+    pid: parse_num(words[3])?,
+    major,
+    minor,
+    ino,
+    start: parse_num(words[5])?,
+    end: if words[6] == b"EOF" {
+      None
+    } else {
+      Some(parse_num(words[6])?)
+    },
+  })
+}
+
+impl FdInfo {
+  /// Parses the contents of an fdinfo file, failing with EINVAL if it's malformed.
+  ///
+  /// Lines this doesn't know about are ignored.
+  pub fn parse(text: &[u8]) -> Result<FdInfo> {
+    let mut pos = None;
+    let mut flags = None;
+    let mut info = FdInfo {
+      pos: 0,
+      flags: OFlag::empty(),
+      mnt_id: None,
+      ino: None,
+      eventfd_count: None,
+      timerfd: None,
+      epoll: Vec::new(),
+      inotify: Vec::new(),
+      locks: Vec::new(),
+    };
+    let mut timerfd = TimerfdInfo {
+      clockid: 0,
+      ticks: 0,
+      settime_flags: 0,
+      it_value: (0, 0),
+      it_interval: (0, 0),
+    };
+    for line in text.split(|b| *b == b'\n') {
+      let line = strip_newline(line);
+      if line.starts_with(b"inotify ") {
+        info.inotify.push(parse_inotify(line)?);
+        continue;
+      }
+      let colon = match line.iter().position(|b| *b == b':') {
+        Some(colon) => colon,
+        None => continue,
+      };
+      let (key, value) = (&line[..colon], trim_start(&line[colon + 1..]));
+      match key {
+        b"pos" => pos = Some(parse_num(value)?),
+        b"flags" => flags = Some(parse_radix(value, 8)? as libc::c_int),
+        b"mnt_id" => info.mnt_id = Some(parse_num(value)?),
+        b"ino" => info.ino = Some(parse_num(value)?),
+        b"eventfd-count" => info.eventfd_count = Some(parse_radix(value, 16)?),
+        b"tfd" => info.epoll.push(parse_epoll(line)?),
+        b"lock" => info.locks.push(parse_lock(value)?),
+        b"clockid" => {
+          timerfd.clockid = parse_num(value)?;
+          info.timerfd = Some(timerfd);
+        }
+        b"ticks" => timerfd.ticks = parse_num(value)?,
+        b"settime flags" => timerfd.settime_flags = parse_radix(value, 8)? as i32,
+        b"it_value" => timerfd.it_value = parse_pair(value)?,
+        b"it_interval" => timerfd.it_interval = parse_pair(value)?,
+        _ => (),
+      }
+    }
+    if info.timerfd.is_some() {
+      info.timerfd = Some(timerfd);
+    }
+    match (pos, flags) {
+      (Some(pos), Some(flags)) => {
+        info.pos = pos;
+        info.flags = OFlag::from_bits_truncate(flags);
+        Ok(info)
+      }
+      _ => invalid(),
+    }
+  }
+}
+
+/// Reads and parses /proc/<pid>/fdinfo/<fd>, for the current process if `pid` is `None`.
+pub fn fdinfo(pid: Option<Pid>, fd: RawFd) -> Result<FdInfo> {
+  let path = match pid {
+    Some(pid) => format!("/proc/{}/fdinfo/{}", pid, fd),
+    None => format!("/proc/self/fdinfo/{}", fd),
+  };
+  let text = read_all(path).map_err(|e| Error::Sys(e.raw_os_error().map_or(Errno::EIO, Errno::from_i32)))?;
+  FdInfo::parse(&text)
+}
+
+/// An open descriptor of some process, as `list_fds` reports it.
+#[derive(Clone, Debug)]
+pub struct OpenFd {
+  pub fd: RawFd,
+  pub target: FdTarget,
+  pub access: AccessMode,
+  pub cloexec: bool,
+  /// What `fstat` says about the open file, or `None` if that failed.
+  #[cfg(not(target_env = "musl"))]
+  pub entry: Option<NodeEntry>,
+}
+
+// Examines `fd`, which is listed as `name` in the /proc fd directory `dirfd`.
+fn inspect_fd(pid: Option<Pid>, dirfd: RawFd, name: &CStr, fd: RawFd) -> Result<OpenFd> {
+  let pid = match pid {
+    Some(pid) => pid,
+    None => {
+      let status = get_status(fd)?;
+      return Ok(OpenFd {
+        fd,
+        target: get_path(fd)?,
+        access: status.access,
+        cloexec: status.cloexec,
+        #[cfg(not(target_env = "musl"))]
+        entry: fstat(fd).ok(),
+      });
+    }
+  };
+  // another process's descriptors can't be used directly, so go through /proc
+  let link = readlinkat_bytes(dirfd, name)?;
+  let flags = fdinfo(Some(pid), fd)?.flags;
+  let target = classify_target(
+    link,
+    |target| {
+      names_file(
+        target,
+        nix::sys::stat::fstatat(dirfd, name, nix::fcntl::AtFlags::empty()),
+      )
+    },
+    // there's no asking about seals here, so go by the name
+    || true,
+  );
+  Ok(OpenFd {
+    fd,
+    target,
+    access: access_mode(flags.bits()),
+    cloexec: flags.contains(OFlag::O_CLOEXEC),
+    #[cfg(not(target_env = "musl"))]
+    entry: fstatat(Some(dirfd), name, Symlink::Follow).ok(),
+  })
+}
+
+/// Lists the open descriptors of process `pid`, or of the current process if that's `None`, in ascending order.
+///
+/// Examining another process needs the same permission as attaching a debugger to it.
+/// Descriptors that are closed while this runs are left out, as is the one it uses itself
+/// to read /proc/self/fd. For another process, `FdTarget::Memfd` is reported for any
+/// deleted file whose name begins "/memfd:".
+pub fn list_fds(pid: Option<Pid>) -> Result<Vec<OpenFd>> {
+  let path = match pid {
+    Some(pid) => format!("/proc/{}/fd", pid),
+    None => "/proc/self/fd".to_string(),
+  };
+  let mut dir = Dir::open(
+    path.as_str(),
+    OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+    Mode::empty(),
+  )?;
+  let dirfd = dir.as_raw_fd();
+  let mut fds = Vec::new();
+  for entry in dir.iter() {
+    let entry = entry?;
+    let name = entry.file_name();
+    let fd = match name.to_str().ok().and_then(|s| s.parse::<RawFd>().ok()) {
+      Some(fd) if pid.is_some() || fd != dirfd => fd,
+      _ => continue,
+    };
+    match inspect_fd(pid, dirfd, name, fd) {
+      Ok(open) => fds.push(open),
+      Err(Error::Sys(Errno::ENOENT)) | Err(Error::Sys(Errno::EBADF)) => (),
+      Err(e) => return Err(e),
+    }
+  }
+  fds.sort_by_key(|open| open.fd);
+  Ok(fds)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_fdinfo() {
+    let text = b"pos:\t0\nflags:\t02004002\nmnt_id:\t15\nino:\t1057\n\
+      tfd:        5 events:       19 data:                7  pos:0 ino:61af sdev:7\n\
+      tfd:       12 events: 80000019 data: ffffffffffffffff  pos:3 ino:2 sdev:d\n\
+      inotify wd:3 ino:9e7e sdev:800013 mask:800afce ignored_mask:0 fhandle-bytes:8 fhandle-type:1 f_handle:7e9e0000640d1b6d\n\
+      lock:\t1: FLOCK  ADVISORY  WRITE 1234 08:01:5678 0 EOF\n\
+      lock:\t2: OFDLCK ADVISORY  READ -1 00:2d:99 100 199\n";
+    let info = FdInfo::parse(text).unwrap();
+    assert_eq!(info.pos, 0);
+    assert_eq!(info.flags, OFlag::O_RDWR | OFlag::O_NONBLOCK | OFlag::O_CLOEXEC);
+    assert_eq!(info.mnt_id, Some(15));
+    assert_eq!(info.ino, Some(1057));
+    assert_eq!(info.eventfd_count, None);
+    assert_eq!(info.timerfd, None);
+    assert_eq!(
+      info.epoll,
+      vec![
+        EpollTarget {
+          tfd: 5,
+          events: 0x19,
+          data: 7,
+          pos: 0,
+          ino: 0x61af,
+          sdev: 7
+        },
+        EpollTarget {
+          tfd: 12,
+          events: 0x8000_0019,
+          data: u64::MAX,
+          pos: 3,
+          ino: 2,
+          sdev: 0xd
+        },
+      ]
+    );
+    assert_eq!(
+      info.inotify,
+      vec![InotifyWatch {
+        wd: 3,
+        ino: 0x9e7e,
+        sdev: 0x80_0013,
+        mask: 0x800_afce,
+        ignored_mask: 0
+      }]
+    );
+    assert_eq!(info.locks.len(), 2);
+    assert_eq!(info.locks[0].kind, "FLOCK");
+    assert_eq!(
+      (info.locks[0].major, info.locks[0].minor, info.locks[0].ino),
+      (8, 1, 5678)
+    );
+    assert_eq!(info.locks[0].end, None);
+    assert_eq!(
+      (info.locks[1].pid, info.locks[1].start, info.locks[1].end),
+      (-1, 100, Some(199))
+    );
+
+    let text = b"pos:\t0\nflags:\t02\nmnt_id:\t15\nclockid: 1\nticks: 4\nsettime flags: 01\n\
+      it_value: (0, 49406829)\nit_interval: (1, 0)\n";
+    let timerfd = FdInfo::parse(text).unwrap().timerfd.unwrap();
+    assert_eq!(timerfd.clockid, libc::CLOCK_MONOTONIC);
+    assert_eq!((timerfd.ticks, timerfd.settime_flags), (4, 1));
+    assert_eq!((timerfd.it_value, timerfd.it_interval), ((0, 49_406_829), (1, 0)));
+
+    assert!(FdInfo::parse(b"flags:\t02\n").is_err());
+    assert!(FdInfo::parse(b"pos:\tx\nflags:\t02\n").is_err());
+  }
+
+  #[test]
+  fn test_fdinfo() {
+    use nix::fcntl::{flock, FlockArg};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::io::AsRawFd;
+    let mut file = tempfile::tempfile().unwrap();
+    file.write_all(b"contents").unwrap();
+    file.seek(SeekFrom::Start(3)).unwrap();
+    flock(file.as_raw_fd(), FlockArg::LockExclusive).unwrap();
+    let info = fdinfo(None, file.as_raw_fd()).unwrap();
+    assert_eq!(info.pos, 3);
+    assert_eq!(info.flags & OFlag::O_ACCMODE, OFlag::O_RDWR);
+    assert_eq!(info.locks.len(), 1);
+    assert_eq!(
+      (info.locks[0].kind.as_str(), info.locks[0].access.as_str()),
+      ("FLOCK", "WRITE")
+    );
+
+    let efd = unsafe { libc::eventfd(5, libc::EFD_CLOEXEC) };
+    let info = fdinfo(Some(nix::unistd::getpid()), efd).unwrap();
+    assert_eq!(info.eventfd_count, Some(5));
+    nix::unistd::close(efd).unwrap();
+
+    assert_eq!(fdinfo(None, -1).err().unwrap().as_errno(), Some(Errno::ENOENT));
+  }
+
+  #[test]
+  fn test_list_fds() {
+    let file = tempfile::tempfile().unwrap();
+    let (r, w) = nix::unistd::pipe().unwrap();
+    let find = |fds: &[OpenFd], fd| fds.iter().find(|open| open.fd == fd).cloned().unwrap();
+    for pid in &[None, Some(nix::unistd::getpid())] {
+      let fds = list_fds(*pid).unwrap();
+      assert!(fds.windows(2).all(|pair| pair[0].fd < pair[1].fd));
+      let open = find(&fds, file.as_raw_fd());
+      assert!(matches!(open.target, FdTarget::DeletedPath(_)));
+      assert_eq!((open.access, open.cloexec), (AccessMode::ReadWrite, true));
+      let open = find(&fds, r);
+      assert_eq!((open.access, open.cloexec), (AccessMode::ReadOnly, false));
+      assert!(matches!(open.target, FdTarget::Pipe(_)));
+      #[cfg(not(target_env = "musl"))]
+      assert_eq!(open.target, FdTarget::Pipe(open.entry.unwrap().st_ino));
+      assert_eq!(find(&fds, w).access, AccessMode::WriteOnly);
+    }
+    nix::unistd::close(r).unwrap();
+    nix::unistd::close(w).unwrap();
+  }
+}
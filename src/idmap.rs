@@ -0,0 +1,347 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, Result};
+
+#[cfg(target_os = "linux")]
+use crate::acl::{get_acl, set_acl, Acl, AclKind, AclTag};
+#[cfg(target_os = "linux")]
+use crate::chown::{fchown_keep_special, walk_tree, ChownReport, Gid, OwnerChange, SpecialBits, TreeWalk, Uid};
+#[cfg(target_os = "linux")]
+use nix::sys::stat::FileStat;
+#[cfg(target_os = "linux")]
+use nix::NixPath;
+#[cfg(target_os = "linux")]
+use std::os::unix::io::RawFd;
+#[cfg(target_os = "linux")]
+use std::path::{Path, PathBuf};
+
+/// One line of a uid or gid map: the `count` ids from `inside` on correspond to those from `outside` on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IdRange {
+  pub inside: u32,
+  pub outside: u32,
+  pub count: u32,
+}
+
+impl IdRange {
+  fn end(start: u32, count: u32) -> u64 {
+    u64::from(start) + u64::from(count)
+  }
+
+  fn overlaps(a: u32, b: u32, count_a: u32, count_b: u32) -> bool {
+    u64::from(a) < Self::end(b, count_b) && u64::from(b) < Self::end(a, count_a)
+  }
+}
+
+/// A table of `IdRange`s, like those newuidmap(1) takes and /proc/<pid>/uid_map shows.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct IdMap {
+  ranges: Vec<IdRange>,
+}
+
+impl IdMap {
+  /// Checks and wraps `ranges`, failing with EINVAL if any is empty or runs past the
+  /// largest id (u32::MAX - 1, since -1 means no id), or if two of them overlap on either side.
+  pub fn new(ranges: Vec<IdRange>) -> Result<Self> {
+    for (i, a) in ranges.iter().enumerate() {
+      let limit = u64::from(u32::MAX);
+      if a.count == 0 || IdRange::end(a.inside, a.count) > limit || IdRange::end(a.outside, a.count) > limit {
+        return Err(Error::Sys(Errno::EINVAL));
+      }
+      for b in &ranges[..i] {
+        if IdRange::overlaps(a.inside, b.inside, a.count, b.count)
+          || IdRange::overlaps(a.outside, b.outside, a.count, b.count)
+        {
+          return Err(Error::Sys(Errno::EINVAL));
+        }
+      }
+    }
+    Ok(IdMap { ranges })
+  }
+
+  pub fn ranges(&self) -> &[IdRange] {
+    &self.ranges
+  }
+
+  /// The outside id that `id` corresponds to, if it's in one of the ranges.
+  pub fn to_outside(&self, id: u32) -> Option<u32> {
+    self
+      .ranges
+      .iter()
+      .find(|r| id >= r.inside && u64::from(id) < IdRange::end(r.inside, r.count))
+      .map(|r| id - r.inside + r.outside)
+  }
+
+  /// The inside id that `id` corresponds to, if it's in one of the ranges.
+  pub fn to_inside(&self, id: u32) -> Option<u32> {
+    self
+      .ranges
+      .iter()
+      .find(|r| id >= r.outside && u64::from(id) < IdRange::end(r.outside, r.count))
+      .map(|r| id - r.outside + r.inside)
+  }
+
+  // Whether some id moves onto an id that moves too, so that mapping twice would move it twice.
+  // (The ids of a range that maps them to themselves don't move, and no other range can land on them.)
+  fn folds(&self) -> bool {
+    self.ranges.iter().filter(|a| a.inside != a.outside).any(|a| {
+      self
+        .ranges
+        .iter()
+        .any(|b| IdRange::overlaps(a.inside, b.outside, a.count, b.count))
+    })
+  }
+}
+
+#[cfg(target_os = "linux")]
+struct Remap<'a> {
+  uids: &'a IdMap,
+  gids: &'a IdMap,
+  report: ChownReport,
+}
+
+#[cfg(target_os = "linux")]
+impl TreeWalk for Remap<'_> {
+  fn skip(&mut self, _st: &FileStat) -> Result<bool> {
+    Ok(false)
+  }
+
+  fn visit<N: ?Sized + NixPath>(
+    &mut self,
+    _dirfd: RawFd,
+    _name: &N,
+    fd: RawFd,
+    st: &FileStat,
+    path: &Path,
+  ) -> Result<()> {
+    let kind = st.st_mode & libc::S_IFMT;
+    // symlinks have no ACLs; change these before the owner, as the owner may need to keep the mode
+    if kind != libc::S_IFLNK {
+      let mut kinds = vec![AclKind::Access];
+      if kind == libc::S_IFDIR {
+        kinds.push(AclKind::Default);
+      }
+      for acl_kind in kinds {
+        if let Err(e) = self.remap_acl(fd, acl_kind) {
+          self.report.errors.push((path.to_path_buf(), e));
+        }
+      }
+    }
+    let old = (Uid::from_raw(st.st_uid), Gid::from_raw(st.st_gid));
+    let owner = self.uids.to_outside(st.st_uid).map(Uid::from_raw);
+    let group = self.gids.to_outside(st.st_gid).map(Gid::from_raw);
+    if owner.is_none() && group.is_none() {
+      return Ok(());
+    }
+    let bits = fchown_keep_special(fd, owner, group)?;
+    self.report.changes.push(OwnerChange {
+      path: path.to_path_buf(),
+      old,
+      new: (owner.unwrap_or(old.0), group.unwrap_or(old.1)),
+    });
+    if let SpecialBits::Lost(e) = bits {
+      self.report.errors.push((path.to_path_buf(), e));
+    }
+    Ok(())
+  }
+
+  fn fail(&mut self, path: PathBuf, e: Error) -> bool {
+    self.report.errors.push((path, e));
+    true
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl Remap<'_> {
+  fn remap_acl(&self, fd: RawFd, kind: AclKind) -> Result<()> {
+    use crate::fd::proc_self_fd;
+    use crate::open::Symlink;
+    let node = proc_self_fd(fd);
+    let acl = match get_acl(None, node.as_c_str(), kind, Symlink::Follow) {
+      Ok(Some(acl)) => acl,
+      Ok(None) | Err(Error::Sys(Errno::EOPNOTSUPP)) => return Ok(()),
+      Err(e) => return Err(e),
+    };
+    let mut changed = false;
+    let mut remapped = Acl::new();
+    for entry in acl.entries() {
+      let tag = match entry.tag {
+        AclTag::User(uid) => self
+          .uids
+          .to_outside(uid.as_raw())
+          .map(|id| AclTag::User(Uid::from_raw(id))),
+        AclTag::Group(gid) => self
+          .gids
+          .to_outside(gid.as_raw())
+          .map(|id| AclTag::Group(Gid::from_raw(id))),
+        _ => None,
+      };
+      changed |= tag.is_some();
+      let tag = tag.unwrap_or(entry.tag);
+      // a moved id can land on one the ACL already names; merging them would lose permissions
+      if remapped.get(tag).is_some() {
+        return Err(Error::Sys(Errno::EEXIST));
+      }
+      remapped.set(tag, entry.perm);
+    }
+    if changed {
+      set_acl(None, node.as_c_str(), kind, &remapped, Symlink::Follow)?;
+    }
+    Ok(())
+  }
+}
+
+/// Moves the owners and groups of `path` and everything under it from the inside of
+/// `uids` and `gids` to the outside, as when shifting a container's root filesystem for
+/// a user namespace. `path` is relative to `dirfd`, or the current working directory if that's `None`.
+///
+/// Symlinks are changed themselves, and never followed. Owner and group ids in the maps
+/// are moved, and so are the ids named in ACL entries; every other id is left alone.
+/// Setuid and setgid bits are kept (see `fchown_keep_special`). An ACL in which a moved id
+/// would land on an id the ACL already names is left as it is, and reported with EEXIST.
+///
+/// This fails with EINVAL, before changing anything, if some id would move onto an id
+/// that moves too. Since every id that moves then lands on one that stays put, the
+/// remapping can safely be run again, say after it's interrupted. Failures for particular
+/// files are collected in the report, as are setuid or setgid bits that couldn't be kept.
+#[cfg(target_os = "linux")]
+pub fn remap_tree<P: ?Sized + NixPath + AsRef<Path>>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  uids: &IdMap,
+  gids: &IdMap,
+) -> Result<ChownReport> {
+  if uids.folds() || gids.folds() {
+    return Err(Error::Sys(Errno::EINVAL));
+  }
+  let mut remap = Remap {
+    uids,
+    gids,
+    report: ChownReport::default(),
+  };
+  walk_tree(
+    &mut remap,
+    dirfd.unwrap_or(libc::AT_FDCWD),
+    path,
+    path.as_ref().to_path_buf(),
+  );
+  Ok(remap.report)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn range(inside: u32, outside: u32, count: u32) -> IdRange {
+    IdRange { inside, outside, count }
+  }
+
+  #[test]
+  fn test_id_map() {
+    let map = IdMap::new(vec![range(0, 100_000, 1000), range(1000, 1000, 1)]).unwrap();
+    assert_eq!(map.to_outside(0), Some(100_000));
+    assert_eq!(map.to_outside(999), Some(100_999));
+    assert_eq!(map.to_outside(1000), Some(1000));
+    assert_eq!(map.to_outside(1001), None);
+    assert_eq!(map.to_inside(100_500), Some(500));
+    assert_eq!(map.to_inside(500), None);
+    assert!(!map.folds());
+    assert!(IdMap::new(vec![range(0, 1, 10)]).unwrap().folds());
+    assert!(IdMap::new(vec![range(0, 100, 10), range(100, 0, 10)]).unwrap().folds());
+    assert!(IdMap::new(vec![range(0, 0, u32::MAX)]).is_ok());
+
+    let einval = Some(Errno::EINVAL);
+    let check = |ranges| IdMap::new(ranges).err().unwrap().as_errno();
+    assert_eq!(check(vec![range(0, 0, 0)]), einval);
+    assert_eq!(check(vec![range(1, 0, u32::MAX)]), einval);
+    assert_eq!(check(vec![range(0, 100, 10), range(9, 200, 1)]), einval);
+    assert_eq!(check(vec![range(0, 100, 10), range(20, 109, 1)]), einval);
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_remap_tree() {
+    use nix::sys::stat::lstat;
+    use std::os::unix::fs::PermissionsExt;
+    let tempdir = tempfile::tempdir().unwrap();
+    let map = IdMap::new(vec![range(0, 100_000, 65536)]).unwrap();
+    let folded = IdMap::new(vec![range(0, 1000, 2000)]).unwrap();
+    assert_eq!(
+      remap_tree(None, tempdir.path(), &folded, &map)
+        .err()
+        .unwrap()
+        .as_errno(),
+      Some(Errno::EINVAL)
+    );
+    if !nix::unistd::geteuid().is_root() {
+      return;
+    }
+    let top = tempdir.path().join("rootfs");
+    let bin = top.join("bin");
+    std::fs::create_dir_all(&bin).unwrap();
+    std::fs::File::create(bin.join("su")).unwrap();
+    std::fs::set_permissions(bin.join("su"), std::fs::Permissions::from_mode(0o4755)).unwrap();
+    std::fs::File::create(top.join("data")).unwrap();
+    nix::unistd::chown(
+      &top.join("data"),
+      Some(Uid::from_raw(1000)),
+      Some(Gid::from_raw(200_000)),
+    )
+    .unwrap();
+    std::fs::File::create(tempdir.path().join("outside")).unwrap();
+    std::os::unix::fs::symlink("../outside", top.join("link")).unwrap();
+    let mut acl = Acl::from_mode(0o750);
+    acl.set(AclTag::User(Uid::from_raw(1000)), crate::perm::Rights::READ);
+    acl.set(AclTag::Group(Gid::from_raw(5)), crate::perm::Rights::READ);
+    acl.update_mask();
+    let acl_support = set_acl(None, &bin, AclKind::Default, &acl, crate::open::Symlink::Fail).is_ok();
+    let shared = top.join("shared");
+    std::fs::File::create(&shared).unwrap();
+    let mut clash = Acl::from_mode(0o640);
+    clash.set(AclTag::User(Uid::from_raw(1000)), crate::perm::Rights::READ);
+    clash.set(
+      AclTag::User(Uid::from_raw(101_000)),
+      crate::perm::Rights::READ | crate::perm::Rights::WRITE,
+    );
+    clash.update_mask();
+    if acl_support {
+      set_acl(None, &shared, AclKind::Access, &clash, crate::open::Symlink::Fail).unwrap();
+    }
+
+    let owner = |path: &Path| {
+      let st = lstat(path).unwrap();
+      (st.st_uid, st.st_gid, st.st_mode & 0o7777)
+    };
+    let report = remap_tree(None, &top, &map, &map).unwrap();
+    if acl_support {
+      assert_eq!(report.errors, vec![(shared.clone(), Error::Sys(Errno::EEXIST))]);
+      let acl = get_acl(None, &shared, AclKind::Access, crate::open::Symlink::Fail)
+        .unwrap()
+        .unwrap();
+      assert_eq!(acl, clash);
+    } else {
+      assert_eq!(report.errors, vec![]);
+    }
+    assert_eq!(report.changes.len(), 6);
+    assert_eq!(owner(&top), (100_000, 100_000, owner(&top).2));
+    assert_eq!(owner(&bin.join("su")), (100_000, 100_000, 0o4755));
+    assert_eq!(owner(&top.join("data")), (101_000, 200_000, owner(&top.join("data")).2));
+    assert_eq!(owner(&top.join("link")).0, 100_000);
+    assert_eq!(owner(&tempdir.path().join("outside")).0, 0);
+    if acl_support {
+      let acl = get_acl(None, &bin, AclKind::Default, crate::open::Symlink::Fail)
+        .unwrap()
+        .unwrap();
+      assert!(acl.get(AclTag::User(Uid::from_raw(101_000))).is_some());
+      assert!(acl.get(AclTag::Group(Gid::from_raw(100_005))).is_some());
+      assert!(acl.get(AclTag::User(Uid::from_raw(1000))).is_none());
+    }
+
+    // running again changes nothing
+    std::fs::remove_file(&shared).unwrap();
+    let report = remap_tree(None, &top, &map, &map).unwrap();
+    assert_eq!(report, ChownReport::default());
+    assert_eq!(owner(&bin.join("su")), (100_000, 100_000, 0o4755));
+  }
+}
@@ -12,11 +12,11 @@ pub use nix::fcntl::{AtFlags, FcntlArg, FdFlag, FlockArg /*OFlag*/};
 pub use nix::sys::sendfile::sendfile;
 pub use nix::sys::stat::{dev_t, mode_t, FchmodatFlags, SFlag /*Mode, UtimensatFlags*/};
 pub use nix::sys::stat::{
-  fchmod, fchmodat, fstat as nix_fstat, fstatat as nix_fstatat, lstat, mkdirat as nix_mkdirat, mknod, stat,
-  umask, /*utimensat, futimens, utimes, lutimes,*/
+  fchmod as nix_fchmod, fchmodat, fstat as nix_fstat, fstatat as nix_fstatat, lstat, mkdirat as nix_mkdirat, mknod,
+  stat, umask, /*utimensat, futimens, utimes, lutimes,*/
 };
 pub use nix::unistd::{
-  access, chown, fchownat, ftruncate, linkat, symlinkat, truncate, unlink, unlinkat as nix_unlinkat,
+  access, chown, fchownat as nix_fchownat, ftruncate, linkat, symlinkat, truncate, unlink, unlinkat as nix_unlinkat,
 };
 pub use nix::unistd::{chdir, fchdir, getcwd, mkdir, mkstemp};
 pub use nix::unistd::{close, dup, dup2, dup3, fsync, lseek, mkfifo, read, write};
@@ -35,25 +35,84 @@ pub use nix::unistd::{
   fdatasync, getgrouplist, getgroups, gettid, initgroups, lseek64, mkfifoat, setgroups, setresgid, setresuid, sync,
 };
 
+// Based on https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/weak.rs
+#[cfg(target_os = "linux")]
+macro_rules! syscall {
+  (fn $name:ident($sysname:ident, $($arg_name:ident: $t:ty),*) -> $ret:ty) => (
+    unsafe fn $name($($arg_name:$t),*) -> $ret {
+      use libc::*;
+      syscall(
+        $sysname,
+        $($arg_name as c_long),*
+      ) as $ret
+    }
+  )
+}
+
+// Whether a syscall that the running kernel may lack can be used, probed once and cached in
+// `state` (0: unknown, 1: not available, 2: available). `probe` makes the call with NULL
+// pointers, which is expected to fail with EFAULT when the syscall is there; it's mainly
+// for performance, being far faster than a successful call. We don't check for ENOSYS,
+// because the syscall may be limited and return EPERM (say from a seccomp filter).
+// See: https://github.com/rust-lang/rust/issues/65662
+#[cfg(target_os = "linux")]
+fn syscall_available<F: FnOnce() -> libc::c_int>(state: &std::sync::atomic::AtomicU8, probe: F) -> bool {
+  use std::sync::atomic::Ordering;
+  match state.load(Ordering::Relaxed) {
+    0 => {
+      let available = probe() == -1 && Errno::last() == Errno::EFAULT;
+      state.store(if available { 2 } else { 1 }, Ordering::Relaxed);
+      available
+    }
+    1 => false,
+    _ => true,
+  }
+}
+
 mod access; // TODO merge into stat?
+#[cfg(target_os = "linux")]
+mod acl;
+mod bytes;
+mod chmod;
 mod chown;
+mod dir;
+mod fd;
+#[cfg(target_os = "linux")]
+mod fdinfo;
+mod idmap;
 mod mkdir;
 mod open;
+mod perm;
 // mod scratch;
 #[cfg(not(target_env = "musl"))]
 mod stat;
 mod temp;
 mod time;
+mod tmpfile;
+#[cfg(target_os = "linux")]
+mod userns;
 
 pub use access::*; // TODO merge into stat?
+#[cfg(target_os = "linux")]
+pub use acl::*;
+pub use chmod::*;
 pub use chown::*;
+pub use dir::*;
+pub use fd::*;
+#[cfg(target_os = "linux")]
+pub use fdinfo::*;
+pub use idmap::*;
 pub use mkdir::*;
 pub use open::*;
+pub use perm::*;
 // pub use scratch::*;
 #[cfg(not(target_env = "musl"))]
 pub use stat::*;
 pub use temp::*;
 pub use time::*;
+pub use tmpfile::*;
+#[cfg(target_os = "linux")]
+pub use userns::*;
 
 #[cfg(test)]
 mod tests {
@@ -65,7 +124,7 @@ mod tests {
     eprintln!("Running under gnu");
     #[cfg(target_env = "musl")]
     eprintln!("Running under musl");
-    #[cfg(target_os = "mac")]
+    #[cfg(target_os = "macos")]
     eprintln!("Running under mac");
     eprintln!("CARGO_BUILTFOR={}", env!("CARGO_BUILTFOR"));
   }
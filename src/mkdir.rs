@@ -53,7 +53,7 @@ fn do_mkdirat(dirfd: RawFd, path: &Path, mode: mode_t) -> Result<()> {
 
 fn do_allat(dirfd: RawFd, path: &Path, mode: mode_t) -> Result<()> {
   // path has is_empty via the NixPath trait
-  if path.is_empty() {
+  if NixPath::is_empty(path) {
     return Ok(());
   }
   match do_mkdirat(dirfd, path, mode) {
@@ -111,6 +111,6 @@ fn do_all(path: &Path, mode: mode_t) -> Result<()> {
 
 #[cfg(test)]
 mod tests {
-  use super::*;
+  // use super::*;
   // TODO
 }
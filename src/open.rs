@@ -3,10 +3,16 @@
 
 use nix::{errno::Errno, /*Error,*/ NixPath, Result};
 use std::os::unix::io::RawFd;
+use std::path::PathBuf;
+
+use crate::fd::OwnedFd;
 
 pub use nix::fcntl::OFlag;
 pub use nix::sys::stat::Mode;
 
+use bitflags::bitflags;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Symlink {
   Follow,
   Open,
@@ -19,18 +25,18 @@ pub fn openat<P: ?Sized + NixPath>(
   oflags: OFlag,
   mode: Mode,
   links: Symlink,
-) -> Result<RawFd> {
+) -> Result<OwnedFd> {
   #[allow(unused_mut)]
   // let mut reject_symlink = false;
   let modebits = libc::c_uint::from(mode.bits());
   let oflags = match links {
     Symlink::Follow => oflags.bits(),
     Symlink::Open => {
-      #[cfg(any(target_os = "linux"))]
+      #[cfg(target_os = "linux")]
       {
         oflags.bits() | libc::O_PATH | libc::O_NOFOLLOW
       }
-      #[cfg(any(target_os = "macos"))]
+      #[cfg(target_os = "macos")]
       {
         // not in libc crate
         const O_SYMLINK: libc::c_int = 0x20_0000;
@@ -49,7 +55,7 @@ pub fn openat<P: ?Sized + NixPath>(
   };
   let fd = path
     .with_nix_path(|cstr| unsafe { libc::openat(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), oflags, modebits) })?;
-  Errno::result(fd)
+  Errno::result(fd).map(|fd| unsafe { OwnedFd::from_raw(fd) })
   /*
   let fd = path.with_nix_path(|cstr| {
     let fd = unsafe { libc::openat(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), oflags, modebits) };
@@ -77,8 +83,1125 @@ pub fn openat<P: ?Sized + NixPath>(
   */
 }
 
+/// Options and flags which can be used to configure how a file is opened,
+/// along the lines of `std::fs::OpenOptions`.
+///
+/// `O_CLOEXEC` is set unless disabled, and newly created files get mode 0o666
+/// (less the umask) unless another `mode` is given. The combination of options is
+/// checked before anything is opened, and invalid combinations fail with EINVAL:
+///
+/// * at least one of `read`, `write` or `append` is needed (except with `Symlink::Open`)
+/// * `truncate`, `create` and `create_new` need `write` or `append`
+/// * `append` can't be combined with `truncate`
+/// * `directory` can't be combined with writing or creating
+/// * `Symlink::Open` can only be combined with `read`, `directory` and `cloexec`,
+///   since the descriptor it yields can't be used for I/O
+#[derive(Clone, Debug)]
+pub struct OpenOptions {
+  read: bool,
+  write: bool,
+  append: bool,
+  truncate: bool,
+  create: bool,
+  create_new: bool,
+  directory: bool,
+  noatime: bool,
+  nonblock: bool,
+  cloexec: bool,
+  mode: Mode,
+  links: Symlink,
+  dirfd: Option<RawFd>,
+}
+
+impl Default for OpenOptions {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl OpenOptions {
+  /// Creates a blank set of options, with `cloexec` set, mode 0o666, `Symlink::Follow`,
+  /// and paths relative to the current working directory.
+  pub fn new() -> Self {
+    OpenOptions {
+      read: false,
+      write: false,
+      append: false,
+      truncate: false,
+      create: false,
+      create_new: false,
+      directory: false,
+      noatime: false,
+      nonblock: false,
+      cloexec: true,
+      mode: Mode::from_bits_truncate(0o666),
+      links: Symlink::Follow,
+      dirfd: None,
+    }
+  }
+
+  pub fn read(&mut self, read: bool) -> &mut Self {
+    self.read = read;
+    self
+  }
+
+  pub fn write(&mut self, write: bool) -> &mut Self {
+    self.write = write;
+    self
+  }
+
+  /// Opens with `O_APPEND`; implies `write`.
+  pub fn append(&mut self, append: bool) -> &mut Self {
+    self.append = append;
+    self
+  }
+
+  pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+    self.truncate = truncate;
+    self
+  }
+
+  /// Creates the file if it doesn't exist.
+  pub fn create(&mut self, create: bool) -> &mut Self {
+    self.create = create;
+    self
+  }
+
+  /// Creates the file, failing with EEXIST if it (or a symlink at `path`) already exists.
+  pub fn create_new(&mut self, create_new: bool) -> &mut Self {
+    self.create_new = create_new;
+    self
+  }
+
+  /// Fails with ENOTDIR unless `path` names a directory.
+  pub fn directory(&mut self, directory: bool) -> &mut Self {
+    self.directory = directory;
+    self
+  }
+
+  /// Opens with `O_NOATIME` (Linux only).
+  pub fn noatime(&mut self, noatime: bool) -> &mut Self {
+    self.noatime = noatime;
+    self
+  }
+
+  pub fn nonblock(&mut self, nonblock: bool) -> &mut Self {
+    self.nonblock = nonblock;
+    self
+  }
+
+  pub fn cloexec(&mut self, cloexec: bool) -> &mut Self {
+    self.cloexec = cloexec;
+    self
+  }
+
+  /// Sets the mode bits for a newly created file.
+  pub fn mode(&mut self, mode: Mode) -> &mut Self {
+    self.mode = mode;
+    self
+  }
+
+  pub fn symlink(&mut self, links: Symlink) -> &mut Self {
+    self.links = links;
+    self
+  }
+
+  /// Resolves relative paths against `dirfd` instead of the current working directory.
+  pub fn dirfd(&mut self, dirfd: RawFd) -> &mut Self {
+    self.dirfd = Some(dirfd);
+    self
+  }
+
+  /// Checks the options and computes the flags that `open` will pass to `openat`.
+  pub fn oflags(&self) -> Result<OFlag> {
+    use nix::Error;
+    let invalid = Err(Error::Sys(Errno::EINVAL));
+    let writing = self.write || self.append;
+    let creating = self.create || self.create_new;
+    if self.links == Symlink::Open {
+      if writing || creating || self.truncate || self.noatime || self.nonblock {
+        return invalid;
+      }
+    } else if !self.read && !writing {
+      return invalid;
+    }
+    if (self.truncate || creating) && !writing {
+      return invalid;
+    }
+    if self.append && self.truncate {
+      return invalid;
+    }
+    if self.directory && (writing || creating || self.truncate) {
+      return invalid;
+    }
+
+    let mut oflags = match (self.read, writing) {
+      (_, false) => OFlag::O_RDONLY,
+      (false, true) => OFlag::O_WRONLY,
+      (true, true) => OFlag::O_RDWR,
+    };
+    if self.append {
+      oflags |= OFlag::O_APPEND;
+    }
+    if self.truncate {
+      oflags |= OFlag::O_TRUNC;
+    }
+    if self.create_new {
+      oflags |= OFlag::O_CREAT | OFlag::O_EXCL;
+    } else if self.create {
+      oflags |= OFlag::O_CREAT;
+    }
+    if self.directory {
+      oflags |= OFlag::O_DIRECTORY;
+    }
+    if self.nonblock {
+      oflags |= OFlag::O_NONBLOCK;
+    }
+    if self.cloexec {
+      oflags |= OFlag::O_CLOEXEC;
+    }
+    if self.noatime {
+      #[cfg(target_os = "linux")]
+      {
+        oflags |= OFlag::O_NOATIME;
+      }
+      #[cfg(not(target_os = "linux"))]
+      {
+        return Err(Error::UnsupportedOperation);
+      }
+    }
+    Ok(oflags)
+  }
+
+  /// Opens the file at `path` with the options specified by `self`.
+  pub fn open<P: ?Sized + NixPath>(&self, path: &P) -> Result<OwnedFd> {
+    let oflags = self.oflags()?;
+    openat(self.dirfd, path, oflags, self.mode, self.links)
+  }
+}
+
+/// How `openat_noatime` managed to open a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AtimeMode {
+  /// Opened with `O_NOATIME`, so reading won't update the file's access time.
+  NoAtime,
+  /// Opened without `O_NOATIME`, which was refused (or isn't available off Linux).
+  Normal,
+}
+
+/// Opens the file at `path` like `openat`, adding `O_NOATIME`; if that fails with EPERM (as
+/// it does for files the caller doesn't own), opens it again without.
+///
+/// `oflags`, `links` and `dirfd` are as for `openat`. The result says which way it was opened.
+///
+/// There's no putting back the access time of a file that had to be opened normally:
+/// setting it needs the same ownership (or `CAP_FOWNER`) whose lack made `O_NOATIME` fail.
+pub fn openat_noatime<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  oflags: OFlag,
+  links: Symlink,
+) -> Result<(OwnedFd, AtimeMode)> {
+  #[cfg(target_os = "linux")]
+  {
+    use nix::Error;
+    #[cfg(test)]
+    let res = if tests::REFUSE_NOATIME.with(|refuse| refuse.get()) {
+      Err(Error::Sys(Errno::EPERM))
+    } else {
+      openat(dirfd, path, oflags | OFlag::O_NOATIME, Mode::empty(), links)
+    };
+    #[cfg(not(test))]
+    let res = openat(dirfd, path, oflags | OFlag::O_NOATIME, Mode::empty(), links);
+    match res {
+      Ok(fd) => return Ok((fd, AtimeMode::NoAtime)),
+      Err(Error::Sys(Errno::EPERM)) => (),
+      Err(e) => return Err(e),
+    }
+  }
+  let fd = openat(dirfd, path, oflags, Mode::empty(), links)?;
+  Ok((fd, AtimeMode::Normal))
+}
+
+/// Opens the file named by `path` without following a final symlink, as `Symlink::Open`
+/// does, but fails with ELOOP if it is one.
+///
+/// This gives `Symlink::Fail` to calls that have no `O_NOFOLLOW` of their own: the type
+/// check and whatever is then done through the descriptor (directly or via `proc_self_fd`)
+/// are both made on the same inode.
+#[cfg(target_os = "linux")]
+pub(crate) fn open_nofollow<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P) -> Result<OwnedFd> {
+  use nix::sys::stat::fstat;
+  use nix::Error;
+  let fd = openat(dirfd, path, OFlag::O_CLOEXEC, Mode::empty(), Symlink::Open)?;
+  if (fstat(fd.as_raw())?.st_mode & libc::S_IFMT) == libc::S_IFLNK {
+    return Err(Error::Sys(Errno::ELOOP));
+  }
+  Ok(fd)
+}
+
+bitflags! {
+  /// Path resolution restrictions for `openat2`; see
+  /// [openat2(2)](http://man7.org/linux/man-pages/man2/openat2.2.html).
+  pub struct ResolveFlag: u64 {
+    /// Fail with EXDEV if any component crosses a mount point.
+    const RESOLVE_NO_XDEV = 0x01;
+    /// Fail with ELOOP on magic links such as /proc/self/fd/N (Linux 5.6).
+    const RESOLVE_NO_MAGICLINKS = 0x02;
+    /// Fail with ELOOP on any symlink; implies RESOLVE_NO_MAGICLINKS.
+    const RESOLVE_NO_SYMLINKS = 0x04;
+    /// Fail with EXDEV if resolution would leave `dirfd` (via "..", an absolute path or a symlink).
+    const RESOLVE_BENEATH = 0x08;
+    /// Treat `dirfd` as the root directory: "..", absolute paths and symlinks are clamped to it.
+    const RESOLVE_IN_ROOT = 0x10;
+    /// Only succeed if the lookup can be done from the dentry cache, else fail with EAGAIN (Linux 5.12).
+    const RESOLVE_CACHED = 0x20;
+  }
+}
+
+#[cfg(target_os = "linux")]
+#[allow(non_camel_case_types, non_upper_case_globals)]
+mod openat2_imports {
+  // not in libc crate before 0.2.7x
+  #[repr(C)]
+  pub struct open_how {
+    pub flags: u64,
+    pub mode: u64,
+    pub resolve: u64,
+  }
+
+  use libc::c_long;
+  #[cfg(target_arch = "mips")]
+  pub const SYS_openat2: c_long = 4437;
+  #[cfg(target_arch = "mips64")]
+  pub const SYS_openat2: c_long = 5437;
+  #[cfg(not(any(target_arch = "mips", target_arch = "mips64")))]
+  pub const SYS_openat2: c_long = 437;
+}
+
+#[cfg(target_os = "linux")]
+#[allow(unused_imports)] // shadowed by libc::* when that has it
+use openat2_imports::SYS_openat2;
+
+#[cfg(target_os = "linux")]
+syscall! {
+    fn sys_openat2(
+        SYS_openat2,
+        fd: libc::c_int,
+        pathname: *const libc::c_char,
+        how: *const openat2_imports::open_how,
+        size: libc::size_t
+    ) -> libc::c_int
+}
+
+#[cfg(target_os = "linux")]
+fn openat2_available() -> bool {
+  use openat2_imports::*;
+  use std::mem::size_of;
+  use std::ptr;
+  use std::sync::atomic::AtomicU8;
+  // Linux kernel prior to 5.6 doesn't support `openat2`
+  static OPENAT2_STATE: AtomicU8 = AtomicU8::new(0);
+  crate::syscall_available(&OPENAT2_STATE, || unsafe {
+    sys_openat2(libc::AT_FDCWD, ptr::null(), ptr::null(), size_of::<open_how>())
+  })
+}
+
+#[cfg(target_os = "linux")]
+unsafe fn try_openat2(
+  dirfd: libc::c_int,
+  path: *const libc::c_char,
+  how: &openat2_imports::open_how,
+) -> Option<Result<OwnedFd>> {
+  if !openat2_available() {
+    return None;
+  }
+  let fd = sys_openat2(dirfd, path, how, std::mem::size_of::<openat2_imports::open_how>());
+  Some(Errno::result(fd).map(|fd| OwnedFd::from_raw(fd)))
+}
+
+/// Reports whether the running kernel provides `openat2` (Linux 5.6 or later).
+///
+/// The answer is probed once and cached.
+pub fn has_openat2() -> bool {
+  #[cfg(target_os = "linux")]
+  {
+    openat2_available()
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    false
+  }
+}
+
+/// Opens the file named by `path` like `openat`, but restricting how the path is
+/// resolved according to `resolve`.
+///
+/// If `dirfd` is `None`, then `path` is relative to the current working directory.
+///
+/// `mode` is only passed to the kernel when `oflags` contains `O_CREAT` (or `O_TMPFILE`);
+/// `openat2` rejects a non-zero mode otherwise. Unlike `openat`, unknown bits in `oflags`
+/// are rejected with EINVAL, and `RESOLVE_CACHED` fails with EINVAL on kernels before 5.12.
+///
+/// Returns `Error::UnsupportedOperation` when the kernel doesn't provide `openat2`
+/// (see `has_openat2`), and on platforms other than Linux.
+///
+/// # References
+///
+/// [openat2(2)](http://man7.org/linux/man-pages/man2/openat2.2.html)
+pub fn openat2<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  oflags: OFlag,
+  mode: Mode,
+  resolve: ResolveFlag,
+) -> Result<OwnedFd> {
+  #[cfg(target_os = "linux")]
+  {
+    use nix::Error;
+    let creating = oflags.contains(OFlag::O_CREAT) || (oflags.bits() & libc::O_TMPFILE) == libc::O_TMPFILE;
+    let how = openat2_imports::open_how {
+      flags: oflags.bits() as u64,
+      mode: if creating { u64::from(mode.bits()) } else { 0 },
+      resolve: resolve.bits(),
+    };
+    path
+      .with_nix_path(|cstr| unsafe { try_openat2(dirfd.unwrap_or(libc::AT_FDCWD), cstr.as_ptr(), &how) })?
+      .unwrap_or(Err(Error::UnsupportedOperation))
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    use nix::Error;
+    let _ = (dirfd, path, oflags, mode, resolve);
+    Err(Error::UnsupportedOperation)
+  }
+}
+
+/// Reads the target of the symlink named by `path` relative to `dirfd`,
+/// growing the buffer as needed instead of truncating at PATH_MAX.
+pub(crate) fn readlinkat_bytes<P: ?Sized + NixPath>(dirfd: RawFd, path: &P) -> Result<Vec<u8>> {
+  path
+    .with_nix_path(|cstr| {
+      let mut buf = Vec::<u8>::with_capacity(libc::PATH_MAX as usize);
+      loop {
+        let res = unsafe {
+          libc::readlinkat(
+            dirfd,
+            cstr.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.capacity() as libc::size_t,
+          )
+        };
+        let len = Errno::result(res)? as usize;
+        if len < buf.capacity() {
+          unsafe { buf.set_len(len) };
+          return Ok(buf);
+        }
+        // possibly truncated: try again with a bigger buffer
+        buf.reserve(buf.capacity() * 2);
+      }
+    })
+    .and_then(|ok| ok)
+}
+
+/// Reads the target of the symlink that `fd` refers to.
+///
+/// This is meant for the `O_PATH` descriptors that `Symlink::Open` gives on Linux, letting a
+/// link be opened once and then read without looking it up by name again. Fails with
+/// ENOENT if `fd` isn't a symlink.
+pub fn freadlink(fd: RawFd) -> Result<PathBuf> {
+  use std::os::unix::ffi::OsStringExt;
+  let target = readlinkat_bytes(fd, "")?;
+  Ok(PathBuf::from(std::ffi::OsString::from_vec(target)))
+}
+
+// Same limit as the kernel's MAXSYMLINKS.
+const MAX_SYMLINKS: usize = 40;
+
+fn push_components(pending: &mut Vec<Vec<u8>>, path: &[u8]) {
+  for comp in path.rsplit(|c| *c == b'/') {
+    if !comp.is_empty() {
+      pending.push(comp.to_vec());
+    }
+  }
+}
+
+fn open_owned(dirfd: RawFd, path: &[u8], oflags: OFlag, mode: Mode) -> Result<OwnedFd> {
+  nix::fcntl::openat(dirfd, path, oflags, mode).map(|fd| unsafe { OwnedFd::from_raw(fd) })
+}
+
+fn check_xdev(fd: &OwnedFd, root_dev: Option<libc::dev_t>) -> Result<()> {
+  use nix::sys::stat::fstat;
+  use nix::Error;
+  match root_dev {
+    Some(dev) if fstat(fd.as_raw())?.st_dev != dev => Err(Error::Sys(Errno::EXDEV)),
+    _ => Ok(()),
+  }
+}
+
+fn is_symlinkat(dirfd: RawFd, comp: &[u8]) -> Result<bool> {
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::fstatat;
+  let stat = fstatat(dirfd, comp, AtFlags::AT_SYMLINK_NOFOLLOW)?;
+  Ok((stat.st_mode & libc::S_IFMT) == libc::S_IFLNK)
+}
+
+fn resolve_beneath(root: RawFd, path: &[u8], oflags: OFlag, mode: Mode, resolve: ResolveFlag) -> Result<OwnedFd> {
+  use nix::fcntl::AtFlags;
+  use nix::sys::stat::{fstat, fstatat};
+  use nix::Error;
+
+  let in_root = resolve.contains(ResolveFlag::RESOLVE_IN_ROOT);
+  let root_dev = if resolve.contains(ResolveFlag::RESOLVE_NO_XDEV) {
+    Some(fstatat(root, ".", AtFlags::empty())?.st_dev)
+  } else {
+    None
+  };
+  #[cfg(target_os = "linux")]
+  let walk_flags = OFlag::O_PATH | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+  #[cfg(not(target_os = "linux"))]
+  let walk_flags = OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_NOFOLLOW | OFlag::O_CLOEXEC;
+  let follow_last = !oflags.contains(OFlag::O_NOFOLLOW);
+  let mut last_flags = oflags | OFlag::O_NOFOLLOW;
+  if path.ends_with(b"/") {
+    last_flags |= OFlag::O_DIRECTORY;
+  }
+
+  if path.is_empty() {
+    return Err(Error::Sys(Errno::ENOENT));
+  }
+  if path[0] == b'/' && !in_root {
+    return Err(Error::Sys(Errno::EXDEV));
+  }
+  let mut pending = Vec::new();
+  push_components(&mut pending, path);
+  // the directories we've descended through, so that ".." can step back out of them
+  let mut stack: Vec<OwnedFd> = Vec::new();
+  let mut links = 0;
+
+  loop {
+    let cur = stack.last().map_or(root, OwnedFd::as_raw);
+    let comp = match pending.pop() {
+      Some(comp) => comp,
+      None => {
+        // the path named a directory we already hold
+        let fd = open_owned(cur, b".", last_flags, mode)?;
+        check_xdev(&fd, root_dev)?;
+        return Ok(fd);
+      }
+    };
+    if comp == b"." {
+      continue;
+    }
+    if comp == b".." {
+      if stack.pop().is_none() && !in_root {
+        return Err(Error::Sys(Errno::EXDEV));
+      }
+      continue;
+    }
+
+    let is_last = pending.is_empty();
+    let res = if is_last {
+      open_owned(cur, &comp, last_flags, mode)
+    } else {
+      open_owned(cur, &comp, walk_flags, Mode::empty())
+    };
+    let symlink = match res {
+      Ok(fd) => {
+        check_xdev(&fd, root_dev)?;
+        // O_PATH|O_NOFOLLOW hands back the symlink itself rather than failing
+        if is_last && follow_last && (oflags.bits() & o_path()) != 0 {
+          if (fstat(fd.as_raw())?.st_mode & libc::S_IFMT) != libc::S_IFLNK {
+            return Ok(fd);
+          }
+          true
+        } else if is_last {
+          return Ok(fd);
+        } else {
+          stack.push(fd);
+          false
+        }
+      }
+      Err(Error::Sys(errno)) if (errno == Errno::ELOOP || errno == Errno::ENOTDIR) && (follow_last || !is_last) => {
+        if is_symlinkat(cur, &comp)? {
+          true
+        } else {
+          return Err(Error::Sys(errno));
+        }
+      }
+      Err(e) => return Err(e),
+    };
+
+    if symlink {
+      if resolve.contains(ResolveFlag::RESOLVE_NO_SYMLINKS) {
+        return Err(Error::Sys(Errno::ELOOP));
+      }
+      links += 1;
+      if links > MAX_SYMLINKS {
+        return Err(Error::Sys(Errno::ELOOP));
+      }
+      let target = readlinkat_bytes(cur, comp.as_slice())?;
+      if target.is_empty() {
+        return Err(Error::Sys(Errno::ENOENT));
+      }
+      if target[0] == b'/' {
+        if !in_root {
+          return Err(Error::Sys(Errno::EXDEV));
+        }
+        stack.clear();
+      }
+      push_components(&mut pending, &target);
+      if is_last && target.ends_with(b"/") {
+        last_flags |= OFlag::O_DIRECTORY;
+      }
+    }
+  }
+}
+
+#[inline]
+pub(crate) fn o_path() -> libc::c_int {
+  #[cfg(target_os = "linux")]
+  {
+    libc::O_PATH
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    0
+  }
+}
+
+/// Opens the file named by `path` without ever leaving the directory `dirfd`,
+/// resolving the path one component at a time in userspace.
+///
+/// This gives the `RESOLVE_BENEATH` and `RESOLVE_IN_ROOT` guarantees of `openat2`
+/// on kernels (and platforms) that lack it. Every component is opened with
+/// `O_NOFOLLOW|O_DIRECTORY` relative to the one before, and symlinks are expanded
+/// here (at most 40 of them) rather than by the kernel.
+///
+/// If `resolve` contains `RESOLVE_IN_ROOT`, then `dirfd` is treated as the root
+/// directory: ".." at the root stays there, and absolute paths and absolute symlinks
+/// start from it. Otherwise `RESOLVE_BENEATH` semantics apply, and any attempt to
+/// escape `dirfd` fails with EXDEV. `RESOLVE_NO_SYMLINKS` and `RESOLVE_NO_XDEV` are
+/// honoured; magic links are never jumped through, since their targets are resolved
+/// as ordinary text, and `RESOLVE_CACHED` is ignored.
+///
+/// If `dirfd` is `None`, then the current working directory is used as the root. If
+/// `resolve` has neither `RESOLVE_BENEATH` nor `RESOLVE_IN_ROOT`, this fails with EINVAL.
+///
+/// The final component is followed if it's a symlink unless `oflags` contains `O_NOFOLLOW`.
+///
+/// Unlike the kernel, this can't stop another process from renaming a directory it's
+/// passing through out from under `dirfd` at the same moment, so that a following ".."
+/// leads outside. Use `openat2` where the guarantee has to hold against that too.
+pub fn openat_beneath<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  oflags: OFlag,
+  mode: Mode,
+  resolve: ResolveFlag,
+) -> Result<OwnedFd> {
+  if !resolve.intersects(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_IN_ROOT) {
+    use nix::Error;
+    return Err(Error::Sys(Errno::EINVAL));
+  }
+  path
+    .with_nix_path(|cstr| resolve_beneath(dirfd.unwrap_or(libc::AT_FDCWD), cstr.to_bytes(), oflags, mode, resolve))
+    .and_then(|ok| ok)
+}
+
+/// Opens the file named by `path` with `openat2` if the kernel provides it, else with
+/// `openat_beneath`, so that `RESOLVE_BENEATH` or `RESOLVE_IN_ROOT` hold either way
+/// (apart from the rename race described for `openat_beneath`).
+///
+/// `resolve` must contain one of those two, or this fails with EINVAL.
+pub fn openat_resolve<P: ?Sized + NixPath>(
+  dirfd: Option<RawFd>,
+  path: &P,
+  oflags: OFlag,
+  mode: Mode,
+  resolve: ResolveFlag,
+) -> Result<OwnedFd> {
+  if !resolve.intersects(ResolveFlag::RESOLVE_BENEATH | ResolveFlag::RESOLVE_IN_ROOT) {
+    use nix::Error;
+    return Err(Error::Sys(Errno::EINVAL));
+  }
+  if has_openat2() {
+    openat2(dirfd, path, oflags, mode, resolve)
+  } else {
+    openat_beneath(dirfd, path, oflags, mode, resolve)
+  }
+}
+
+/// Opens the file that `fd` refers to again, with access mode and flags `oflags`.
+///
+/// This upgrades a read-only or `O_PATH` descriptor (for example one already checked with
+/// `fstat`) into a handle with different access to exactly the same inode, without
+/// looking the file up by name again. It goes through the /proc/self/fd magic link,
+/// so is only available on Linux; elsewhere it fails with `UnsupportedOperation`.
+///
+/// `O_CREAT`, `O_EXCL` and `O_TMPFILE` give EINVAL. If `fd` refers to a symlink (see
+/// `Symlink::Open`), this fails with ELOOP rather than open whatever the link points to.
+/// So `O_NOFOLLOW` has nothing to add, and is dropped: passed on, it would make opening
+/// the magic link itself fail with ELOOP. And if the result turns out not to be the same
+/// device and inode as `fd`, as when /proc isn't what it should be, it fails with EXDEV.
+pub fn reopen(fd: RawFd, oflags: OFlag) -> Result<OwnedFd> {
+  use nix::Error;
+  #[cfg(target_os = "linux")]
+  {
+    use crate::fd::proc_self_fd;
+    use nix::sys::stat::fstat;
+
+    if oflags.intersects(OFlag::O_CREAT | OFlag::O_EXCL) || (oflags.bits() & libc::O_TMPFILE) == libc::O_TMPFILE {
+      return Err(Error::Sys(Errno::EINVAL));
+    }
+    let oflags = oflags - OFlag::O_NOFOLLOW;
+    let before = fstat(fd)?;
+    if (before.st_mode & libc::S_IFMT) == libc::S_IFLNK {
+      return Err(Error::Sys(Errno::ELOOP));
+    }
+    let proc_path = proc_self_fd(fd);
+    let res = unsafe { libc::open(proc_path.as_ptr(), oflags.bits()) };
+    let newfd = unsafe { OwnedFd::from_raw(Errno::result(res)?) };
+    let after = fstat(newfd.as_raw())?;
+    if after.st_dev != before.st_dev || after.st_ino != before.st_ino {
+      return Err(Error::Sys(Errno::EXDEV));
+    }
+    Ok(newfd)
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    let _ = (fd, oflags);
+    Err(Error::UnsupportedOperation)
+  }
+}
+
 #[cfg(test)]
 mod tests {
   use super::*;
-  // TODO
+  use nix::fcntl::open;
+  use std::fs::File;
+
+  thread_local! {
+    // makes openat_noatime behave as if O_NOATIME were refused
+    pub(super) static REFUSE_NOATIME: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_openat2() {
+    if !has_openat2() {
+      eprintln!("openat2 not available, skipping");
+      return;
+    }
+    let tempdir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(tempdir.path().join("sub")).unwrap();
+    let _file = File::create(tempdir.path().join("file")).unwrap();
+    std::os::unix::fs::symlink("../file", tempdir.path().join("sub/up")).unwrap();
+    std::os::unix::fs::symlink("/file", tempdir.path().join("sub/abs")).unwrap();
+    let dirfd = open(tempdir.path(), OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+    let subfd = openat2(
+      Some(dirfd),
+      "sub",
+      OFlag::O_DIRECTORY,
+      Mode::empty(),
+      ResolveFlag::empty(),
+    )
+    .unwrap();
+
+    let fd = openat2(
+      Some(subfd.as_raw()),
+      "up",
+      OFlag::O_RDONLY,
+      Mode::empty(),
+      ResolveFlag::empty(),
+    )
+    .unwrap();
+    fd.close().unwrap();
+    assert_eq!(
+      openat2(
+        Some(subfd.as_raw()),
+        "up",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        ResolveFlag::RESOLVE_BENEATH
+      )
+      .err()
+      .unwrap()
+      .as_errno(),
+      Some(Errno::EXDEV)
+    );
+    assert_eq!(
+      openat2(
+        Some(subfd.as_raw()),
+        "up",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        ResolveFlag::RESOLVE_NO_SYMLINKS
+      )
+      .err()
+      .unwrap()
+      .as_errno(),
+      Some(Errno::ELOOP)
+    );
+    // "/file" is resolved inside dirfd rather than the real root
+    let fd = openat2(
+      Some(dirfd),
+      "sub/abs",
+      OFlag::O_RDONLY,
+      Mode::empty(),
+      ResolveFlag::RESOLVE_IN_ROOT,
+    )
+    .unwrap();
+    fd.close().unwrap();
+    assert_eq!(
+      openat2(
+        Some(dirfd),
+        "sub/abs",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        ResolveFlag::empty()
+      )
+      .err()
+      .unwrap()
+      .as_errno(),
+      Some(Errno::ENOENT)
+    );
+
+    let fd = openat2(
+      Some(dirfd),
+      "created",
+      OFlag::O_CREAT | OFlag::O_WRONLY,
+      Mode::from_bits_truncate(0o600),
+      ResolveFlag::RESOLVE_BENEATH,
+    )
+    .unwrap();
+    fd.close().unwrap();
+    subfd.close().unwrap();
+    nix::unistd::close(dirfd).unwrap();
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_freadlink() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("link");
+    std::os::unix::fs::symlink("some/target", &path).unwrap();
+    let fd = openat(None, &path, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    assert_eq!(freadlink(fd.as_raw()).unwrap(), PathBuf::from("some/target"));
+    let fd = openat(None, tempdir.path(), OFlag::O_PATH, Mode::empty(), Symlink::Follow).unwrap();
+    assert_eq!(freadlink(fd.as_raw()).err().unwrap().as_errno(), Some(Errno::ENOENT));
+  }
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_reopen() {
+    use nix::fcntl::{fcntl, FcntlArg};
+    use std::io::{Read, Write};
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    File::create(&path).unwrap();
+
+    let fd = openat(None, &path, OFlag::O_PATH, Mode::empty(), Symlink::Follow).unwrap();
+    let wfd = reopen(fd.as_raw(), OFlag::O_WRONLY | OFlag::O_APPEND | OFlag::O_CLOEXEC).unwrap();
+    let flags = OFlag::from_bits_truncate(fcntl(wfd.as_raw(), FcntlArg::F_GETFL).unwrap());
+    assert_eq!(flags & OFlag::O_ACCMODE, OFlag::O_WRONLY);
+    assert!(flags.contains(OFlag::O_APPEND));
+    File::from(wfd).write_all(b"hello").unwrap();
+
+    // the reopened handle follows the inode, not the name
+    std::fs::rename(&path, tempdir.path().join("moved")).unwrap();
+    File::create(&path).unwrap();
+    let mut buf = String::new();
+    File::from(reopen(fd.as_raw(), OFlag::O_RDONLY).unwrap())
+      .read_to_string(&mut buf)
+      .unwrap();
+    assert_eq!(buf, "hello");
+
+    let e = reopen(fd.as_raw(), OFlag::O_RDWR | OFlag::O_CREAT).err().unwrap();
+    assert_eq!(e.as_errno(), Some(Errno::EINVAL));
+    let e = reopen(fd.as_raw(), OFlag::O_RDWR | OFlag::O_TMPFILE).err().unwrap();
+    assert_eq!(e.as_errno(), Some(Errno::EINVAL));
+    let e = reopen(fd.as_raw(), OFlag::O_RDONLY | OFlag::O_EXCL).err().unwrap();
+    assert_eq!(e.as_errno(), Some(Errno::EINVAL));
+    // the magic link is always a symlink, so O_NOFOLLOW can't be passed on
+    reopen(fd.as_raw(), OFlag::O_RDONLY | OFlag::O_NOFOLLOW).unwrap();
+
+    // O_DIRECTORY shares a bit with O_TMPFILE, but on its own is fine
+    let dfd = openat(None, tempdir.path(), OFlag::O_PATH, Mode::empty(), Symlink::Follow).unwrap();
+    let rfd = reopen(dfd.as_raw(), OFlag::O_RDONLY | OFlag::O_DIRECTORY).unwrap();
+    assert!(nix::dir::Dir::from_fd(rfd.into_raw())
+      .unwrap()
+      .iter()
+      .any(|e| e.unwrap().file_name().to_bytes() == b"moved"));
+
+    let link = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &link).unwrap();
+    let fd = openat(None, &link, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    let e = reopen(fd.as_raw(), OFlag::O_RDONLY).err().unwrap();
+    assert_eq!(e.as_errno(), Some(Errno::ELOOP));
+  }
+
+  #[test]
+  fn test_openat_noatime() {
+    use crate::time::{futime, TimeSpec, TimeValLike};
+    use nix::fcntl::{fcntl, FcntlArg};
+    use nix::sys::stat::fstat;
+    use std::os::unix::io::AsRawFd;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    std::fs::write(&path, b"contents").unwrap();
+    let (atime, mtime) = (TimeSpec::seconds(1000), TimeSpec::seconds(2000));
+    futime(File::open(&path).unwrap().as_raw_fd(), &atime, &mtime).unwrap();
+
+    let (fd, mode) = openat_noatime(None, &path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Symlink::Follow).unwrap();
+    // running as root or as the file's owner, O_NOATIME is allowed
+    if cfg!(target_os = "linux") {
+      assert_eq!(mode, AtimeMode::NoAtime);
+      nix::unistd::read(fd.as_raw(), &mut [0; 8]).unwrap();
+      assert_eq!(fstat(fd.as_raw()).unwrap().st_atime, 1000);
+    } else {
+      assert_eq!(mode, AtimeMode::Normal);
+    }
+    fd.close().unwrap();
+
+    REFUSE_NOATIME.with(|refuse| refuse.set(true));
+    let (fd, mode) = openat_noatime(None, &path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Symlink::Follow).unwrap();
+    REFUSE_NOATIME.with(|refuse| refuse.set(false));
+    assert_eq!(mode, AtimeMode::Normal);
+    let flags = OFlag::from_bits_truncate(fcntl(fd.as_raw(), FcntlArg::F_GETFL).unwrap());
+    assert!(!flags.contains(OFlag::O_NOATIME));
+    fd.close().unwrap();
+
+    // other errors aren't retried
+    let e = openat_noatime(None, &tempdir.path().join("missing"), OFlag::O_RDONLY, Symlink::Follow)
+      .err()
+      .unwrap();
+    assert_eq!(e.as_errno(), Some(Errno::ENOENT));
+  }
+
+  #[test]
+  fn test_open_options() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dirfd = open(tempdir.path(), OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+    let einval = Some(Errno::EINVAL);
+    assert_eq!(OpenOptions::new().oflags().err().unwrap().as_errno(), einval);
+    assert_eq!(
+      OpenOptions::new()
+        .read(true)
+        .create(true)
+        .oflags()
+        .err()
+        .unwrap()
+        .as_errno(),
+      einval
+    );
+    assert_eq!(
+      OpenOptions::new()
+        .append(true)
+        .truncate(true)
+        .oflags()
+        .err()
+        .unwrap()
+        .as_errno(),
+      einval
+    );
+    assert_eq!(
+      OpenOptions::new()
+        .write(true)
+        .directory(true)
+        .oflags()
+        .err()
+        .unwrap()
+        .as_errno(),
+      einval
+    );
+    assert_eq!(
+      OpenOptions::new()
+        .symlink(Symlink::Open)
+        .write(true)
+        .oflags()
+        .err()
+        .unwrap()
+        .as_errno(),
+      einval
+    );
+    assert_eq!(
+      OpenOptions::new().read(true).append(true).oflags().unwrap(),
+      OFlag::O_RDWR | OFlag::O_APPEND | OFlag::O_CLOEXEC
+    );
+    assert_eq!(
+      OpenOptions::new().read(true).cloexec(false).oflags().unwrap(),
+      OFlag::O_RDONLY
+    );
+
+    let fd = OpenOptions::new()
+      .write(true)
+      .create_new(true)
+      .mode(Mode::from_bits_truncate(0o600))
+      .dirfd(dirfd)
+      .open("file")
+      .unwrap();
+    let flags = nix::fcntl::fcntl(fd.as_raw(), nix::fcntl::FcntlArg::F_GETFD).unwrap();
+    assert_ne!(flags & libc::FD_CLOEXEC, 0);
+    fd.close().unwrap();
+    let stat = nix::sys::stat::stat(&tempdir.path().join("file")).unwrap();
+    assert_eq!(stat.st_mode & 0o777, 0o600);
+    assert_eq!(
+      OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .dirfd(dirfd)
+        .open("file")
+        .err()
+        .unwrap()
+        .as_errno(),
+      Some(Errno::EEXIST)
+    );
+    nix::unistd::close(dirfd).unwrap();
+  }
+
+  fn outcome(res: Result<OwnedFd>) -> std::result::Result<(u64, u64), Errno> {
+    match res {
+      Ok(fd) => {
+        let stat = nix::sys::stat::fstat(fd.as_raw()).unwrap();
+        Ok((stat.st_dev as u64, stat.st_ino as u64))
+      }
+      Err(e) => Err(e.as_errno().unwrap()),
+    }
+  }
+
+  #[test]
+  fn test_openat_beneath() {
+    use std::os::unix::fs::symlink;
+    let tempdir = tempfile::tempdir().unwrap();
+    let root = tempdir.path().join("root");
+    std::fs::create_dir_all(root.join("dir/inner")).unwrap();
+    let _file = File::create(root.join("file")).unwrap();
+    let _outside = File::create(tempdir.path().join("outside")).unwrap();
+    symlink("../file", root.join("dir/up")).unwrap();
+    symlink("../../outside", root.join("dir/escape")).unwrap();
+    symlink("/file", root.join("dir/abs")).unwrap();
+    symlink("loop", root.join("dir/loop")).unwrap();
+    symlink("../dir/", root.join("dir/todir")).unwrap();
+    let dirfd = open(&root, OFlag::O_DIRECTORY, Mode::empty()).unwrap();
+    let file = outcome(openat(
+      None,
+      &root.join("file"),
+      OFlag::O_RDONLY,
+      Mode::empty(),
+      Symlink::Follow,
+    ));
+    let dir = outcome(openat(
+      None,
+      &root.join("dir"),
+      OFlag::O_RDONLY,
+      Mode::empty(),
+      Symlink::Follow,
+    ));
+    let inner = outcome(openat(
+      None,
+      &root.join("dir/inner"),
+      OFlag::O_RDONLY,
+      Mode::empty(),
+      Symlink::Follow,
+    ));
+    let exdev = Err(Errno::EXDEV);
+
+    let beneath = ResolveFlag::RESOLVE_BENEATH;
+    let in_root = ResolveFlag::RESOLVE_IN_ROOT;
+    #[rustfmt::skip]
+    let cases = [
+      ("file", beneath, file), ("file", in_root, file),
+      ("dir/up", beneath, file), ("dir/up", in_root, file),
+      ("dir/../file", beneath, file),
+      ("dir/escape", beneath, exdev), ("dir/escape", in_root, Err(Errno::ENOENT)),
+      ("dir/abs", beneath, exdev), ("dir/abs", in_root, file),
+      ("/file", beneath, exdev), ("/file", in_root, file),
+      ("../root/file", beneath, exdev), ("../../dir", in_root, dir),
+      ("dir/loop", beneath, Err(Errno::ELOOP)),
+      ("dir/todir/inner", beneath, inner), ("dir/todir/../file", in_root, file),
+      ("dir/", beneath, dir), ("file/", beneath, Err(Errno::ENOTDIR)),
+      ("dir/inner/..", beneath, dir), ("nonexistent", beneath, Err(Errno::ENOENT)),
+    ];
+    for (path, resolve, expected) in cases.iter() {
+      let got = outcome(openat_beneath(
+        Some(dirfd),
+        *path,
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        *resolve,
+      ));
+      assert_eq!(got, *expected, "openat_beneath({:?}, {:?})", path, resolve);
+      if has_openat2() {
+        let got = outcome(openat2(Some(dirfd), *path, OFlag::O_RDONLY, Mode::empty(), *resolve));
+        assert_eq!(got, *expected, "openat2({:?}, {:?})", path, resolve);
+      }
+    }
+
+    assert_eq!(
+      outcome(openat_beneath(
+        Some(dirfd),
+        "dir/up",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        beneath | ResolveFlag::RESOLVE_NO_SYMLINKS
+      )),
+      Err(Errno::ELOOP)
+    );
+    // without BENEATH or IN_ROOT, openat2 wouldn't confine the path, so neither is allowed
+    assert_eq!(
+      outcome(openat_beneath(
+        Some(dirfd),
+        "file",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        ResolveFlag::empty()
+      )),
+      Err(Errno::EINVAL)
+    );
+    assert_eq!(
+      outcome(openat_resolve(
+        Some(dirfd),
+        "../outside",
+        OFlag::O_RDONLY,
+        Mode::empty(),
+        ResolveFlag::RESOLVE_NO_XDEV
+      )),
+      Err(Errno::EINVAL)
+    );
+    #[cfg(target_os = "linux")]
+    {
+      assert_eq!(
+        outcome(openat_beneath(
+          Some(dirfd),
+          "dir/up",
+          OFlag::O_PATH,
+          Mode::empty(),
+          beneath
+        )),
+        file
+      );
+      assert_ne!(
+        outcome(openat_beneath(
+          Some(dirfd),
+          "dir/up",
+          OFlag::O_PATH | OFlag::O_NOFOLLOW,
+          Mode::empty(),
+          beneath
+        )),
+        file
+      );
+    }
+    let fd = openat_beneath(
+      Some(dirfd),
+      "dir/inner/../new",
+      OFlag::O_CREAT | OFlag::O_WRONLY,
+      Mode::from_bits_truncate(0o600),
+      beneath,
+    )
+    .unwrap();
+    fd.close().unwrap();
+    assert!(root.join("dir/new").is_file());
+    nix::unistd::close(dirfd).unwrap();
+  }
 }
@@ -0,0 +1,201 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use crate::chown::{Gid, Uid};
+#[cfg(not(target_env = "musl"))]
+use crate::stat::NodeEntry;
+use bitflags::bitflags;
+use nix::sys::stat::mode_t;
+
+bitflags! {
+  /// Access rights to a file, with the same values as the `R_OK`, `W_OK` and `X_OK` flags.
+  ///
+  /// For a directory, `EXEC` means permission to search it.
+  pub struct Rights: u8 {
+    const READ = 0o4;
+    const WRITE = 0o2;
+    const EXEC = 0o1;
+  }
+}
+
+/// The credentials whose access to a file is to be evaluated.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Credentials {
+  pub uid: Uid,
+  pub gid: Gid,
+  /// Supplementary groups.
+  pub groups: Vec<Gid>,
+  /// Whether the permission bits are overridden, as for root (`CAP_DAC_OVERRIDE` and `CAP_FOWNER`).
+  pub privileged: bool,
+}
+
+impl Credentials {
+  /// Credentials for `uid` and `gid`, privileged if `uid` is root.
+  pub fn new(uid: Uid, gid: Gid, groups: Vec<Gid>) -> Self {
+    Credentials {
+      uid,
+      gid,
+      groups,
+      privileged: uid.is_root(),
+    }
+  }
+
+  /// The calling process's effective credentials.
+  pub fn current() -> nix::Result<Self> {
+    #[cfg(target_os = "linux")]
+    let groups = nix::unistd::getgroups()?;
+    #[cfg(not(target_os = "linux"))]
+    let groups = Vec::new();
+    Ok(Self::new(nix::unistd::geteuid(), nix::unistd::getegid(), groups))
+  }
+
+  fn in_group(&self, gid: Gid) -> bool {
+    self.gid == gid || self.groups.contains(&gid)
+  }
+}
+
+/// The rights that `creds` have to a file with permission bits and type `mode`, owned by `owner` and `group`.
+///
+/// As the kernel does, only one class of the permission bits applies: the owner's if `creds`
+/// are the owner's, even when the group or others get more; otherwise the group's if `creds`
+/// include that group; otherwise the others'. Privileged credentials may read and write
+/// anything, but execute a non-directory only if some class may execute it.
+///
+/// ACLs, capabilities other than those of root, read-only mounts and immutable files aren't considered.
+pub fn mode_rights(mode: mode_t, owner: Uid, group: Gid, creds: &Credentials) -> Rights {
+  if creds.privileged {
+    let is_dir = (mode & libc::S_IFMT) == libc::S_IFDIR;
+    return if is_dir || mode & 0o111 != 0 {
+      Rights::all()
+    } else {
+      Rights::READ | Rights::WRITE
+    };
+  }
+  let bits = if creds.uid == owner {
+    mode >> 6
+  } else if creds.in_group(group) {
+    mode >> 3
+  } else {
+    mode
+  };
+  Rights::from_bits_truncate((bits & 0o7) as u8)
+}
+
+/// The rights that `creds` have to the file `entry` describes; see `mode_rights`.
+#[cfg(not(target_env = "musl"))]
+pub fn rights(entry: &NodeEntry, creds: &Credentials) -> Rights {
+  mode_rights(
+    entry.st_mode,
+    Uid::from_raw(entry.st_uid),
+    Gid::from_raw(entry.st_gid),
+    creds,
+  )
+}
+
+/// Whether `creds` may remove or rename the file `child` from the directory `parent`.
+///
+/// That needs write and search rights to `parent`. If `parent` has its sticky bit set
+/// (like /tmp), `creds` must also own `child` or `parent`, or be privileged.
+#[cfg(not(target_env = "musl"))]
+pub fn can_delete(parent: &NodeEntry, child: &NodeEntry, creds: &Credentials) -> bool {
+  let parent_owner = Uid::from_raw(parent.st_uid);
+  may_delete(
+    parent.st_mode,
+    parent_owner,
+    Gid::from_raw(parent.st_gid),
+    Uid::from_raw(child.st_uid),
+    creds,
+  )
+}
+
+fn may_delete(
+  parent_mode: mode_t,
+  parent_owner: Uid,
+  parent_group: Gid,
+  child_owner: Uid,
+  creds: &Credentials,
+) -> bool {
+  let parent_rights = mode_rights(parent_mode, parent_owner, parent_group, creds);
+  if !parent_rights.contains(Rights::WRITE | Rights::EXEC) {
+    return false;
+  }
+  if parent_mode & libc::S_ISVTX == 0 || creds.privileged {
+    return true;
+  }
+  creds.uid == child_owner || creds.uid == parent_owner
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn creds(uid: u32, gid: u32, groups: &[u32]) -> Credentials {
+    Credentials::new(
+      Uid::from_raw(uid),
+      Gid::from_raw(gid),
+      groups.iter().map(|g| Gid::from_raw(*g)).collect(),
+    )
+  }
+
+  #[test]
+  fn test_mode_rights() {
+    let (owner, group) = (Uid::from_raw(1000), Gid::from_raw(100));
+    let check = |mode, creds: &Credentials| mode_rights(libc::S_IFREG | mode, owner, group, creds);
+    let rw = Rights::READ | Rights::WRITE;
+    assert_eq!(check(0o640, &creds(1000, 5, &[])), rw);
+    assert_eq!(check(0o640, &creds(2000, 100, &[])), Rights::READ);
+    assert_eq!(check(0o640, &creds(2000, 5, &[7, 100])), Rights::READ);
+    assert_eq!(check(0o640, &creds(2000, 5, &[])), Rights::empty());
+    // the owner's class applies even when it's the most restrictive
+    assert_eq!(check(0o077, &creds(1000, 100, &[])), Rights::empty());
+    assert_eq!(check(0o707, &creds(2000, 100, &[])), Rights::empty());
+    assert_eq!(check(0o705, &creds(2000, 5, &[])), Rights::READ | Rights::EXEC);
+
+    // root may execute only if someone can
+    assert_eq!(check(0o000, &creds(0, 0, &[])), rw);
+    assert_eq!(check(0o001, &creds(0, 0, &[])), Rights::all());
+    assert_eq!(
+      mode_rights(libc::S_IFDIR, owner, group, &creds(0, 0, &[])),
+      Rights::all()
+    );
+    let mut unprivileged = creds(0, 0, &[]);
+    unprivileged.privileged = false;
+    assert_eq!(check(0o604, &unprivileged), Rights::READ);
+  }
+
+  #[test]
+  fn test_may_delete() {
+    let (owner, group, child) = (Uid::from_raw(1000), Gid::from_raw(100), Uid::from_raw(2000));
+    let check = |mode, creds: &Credentials| may_delete(libc::S_IFDIR | mode, owner, group, child, creds);
+    assert!(check(0o1777, &creds(2000, 5, &[])));
+    assert!(check(0o1777, &creds(1000, 5, &[])));
+    assert!(!check(0o1777, &creds(3000, 5, &[])));
+    assert!(check(0o1777, &creds(0, 0, &[])));
+    assert!(check(0o777, &creds(3000, 5, &[])));
+    assert!(!check(0o775, &creds(3000, 5, &[])));
+    assert!(check(0o775, &creds(3000, 5, &[100])));
+    assert!(!check(0o776, &creds(3000, 5, &[])));
+    assert!(!check(0o755, &creds(2000, 5, &[])));
+  }
+
+  #[test]
+  #[cfg(not(target_env = "musl"))]
+  fn test_rights() {
+    use crate::open::Symlink;
+    use crate::stat::fstatat;
+    use std::os::unix::fs::PermissionsExt;
+    let tempdir = tempfile::tempdir().unwrap();
+    let path = tempdir.path().join("file");
+    std::fs::File::create(&path).unwrap();
+    std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o640)).unwrap();
+    let parent = fstatat(None, tempdir.path(), Symlink::Follow).unwrap();
+    let entry = fstatat(None, &path, Symlink::Follow).unwrap();
+    let mut me = Credentials::current().unwrap();
+    me.privileged = false;
+    assert_eq!(rights(&entry, &me), Rights::READ | Rights::WRITE);
+    assert!(can_delete(&parent, &entry, &me));
+    let stranger = creds(65000, 65000, &[]);
+    assert_eq!(rights(&entry, &stranger), Rights::empty());
+    assert!(!can_delete(&parent, &entry, &stranger));
+  }
+}
@@ -19,6 +19,7 @@ use stat_imports::*;
   target_os = "netbsd",
   target_os = "macos"
 ))]
+#[allow(unused_imports)]
 mod stat_imports {
   pub use libc::{blkcnt_t, blksize_t, dev_t, gid_t, ino_t, mode_t, nlink_t, off_t, time_t, uid_t};
   pub(super) use libc::{fstat as fstat64, fstatat as fstatat64, stat as stat64};
@@ -26,6 +27,7 @@ mod stat_imports {
 }
 
 #[cfg(target_env = "uclibc")]
+#[allow(unused_imports)]
 mod stat_imports {
   pub use libc::{blkcnt64_t as blkcnt_t, ino64_t as ino_t, off64_t as off_t};
   pub use libc::{blksize_t, gid_t, mode_t, nlink_t, time_t, uid_t};
@@ -38,6 +40,7 @@ mod stat_imports {
 }
 
 #[cfg(all(not(target_env = "uclibc"), any(target_os = "linux", target_os = "emscripten")))]
+#[allow(unused_imports)]
 mod stat_imports {
   pub use libc::{blkcnt64_t as blkcnt_t, ino64_t as ino_t, off64_t as off_t};
   pub use libc::{blksize_t, gid_t, mode_t, nlink_t, time_t, uid_t};
@@ -50,6 +53,7 @@ mod stat_imports {
 }
 
 #[cfg(target_os = "android")]
+#[allow(unused_imports)]
 mod stat_imports {
   pub use libc::{blkcnt_t, gid_t, ino_t, off64_t as off_t, time_t, uid_t};
   pub(super) use libc::{fstat as fstat64, fstatat as fstatat64, stat as stat64};
@@ -72,20 +76,6 @@ mod stat_imports {
   pub type blksize_t = i64; // libc::blksize_t is u64
 }
 
-// Based on https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/weak.rs
-#[cfg(all(target_os = "linux", any(target_env = "gnu", target_env = "musl")))]
-macro_rules! syscall {
-  (fn $name:ident($sysname:ident, $($arg_name:ident: $t:ty),*) -> $ret:ty) => (
-    unsafe fn $name($($arg_name:$t),*) -> $ret {
-      use libc::*;
-      syscall(
-        $sysname,
-        $($arg_name as c_long),*
-      ) as $ret
-    }
-  )
-}
-
 // Based on https://github.com/rust-lang/rust/blob/master/src/libstd/sys/unix/fs.rs
 
 macro_rules! cfg_has_statx {
@@ -286,15 +276,11 @@ cfg_has_statx! {{
     flags: i32,
     mask: u32,
   ) -> Option<Result<NodeEntry>> {
-    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::atomic::AtomicU8;
     use std::ptr;
     use nix::Error;
 
     // Linux kernel prior to 4.11 or glibc prior to glibc 2.28 don't support `statx`
-    // We store the availability in global to avoid unnecessary syscalls.
-    // 0: Unknown
-    // 1: Not available
-    // 2: Available
     static STATX_STATE: AtomicU8 = AtomicU8::new(0);
     syscall! {
         fn statx(
@@ -307,34 +293,8 @@ cfg_has_statx! {{
         ) -> libc::c_int
     }
 
-    match STATX_STATE.load(Ordering::Relaxed) {
-      0 => {
-        // It is a trick to call `statx` with NULL pointers to check if the syscall
-        // is available. According to the manual, it is expected to fail with EFAULT.
-        // We do this mainly for performance, since it is nearly hundreds times
-        // faster than a normal successful call.
-        let res = statx(0, ptr::null(), 0, STATX_ALL, ptr::null_mut());
-        /*
-        let err = Errno::result(res)
-          .err() // Result<T, E> -> Option<E>
-          .and_then(|e| e.as_errno());
-        */
-        let err = if res == -1 {
-          Some(Errno::last())
-        } else {
-          None
-        };
-        // We don't check `err == Some(ENOSYS)` because the syscall may be limited
-        // and returns `EPERM`. Listing all possible errors seems not a good idea.
-        // See: https://github.com/rust-lang/rust/issues/65662
-        if err != Some(Errno::EFAULT) {
-          STATX_STATE.store(1, Ordering::Relaxed);
-          return None;
-        }
-        STATX_STATE.store(2, Ordering::Relaxed);
-      }
-      1 => return None,
-      _ => {}
+    if !crate::syscall_available(&STATX_STATE, || statx(0, ptr::null(), 0, STATX_ALL, ptr::null_mut())) {
+      return None;
     }
 
     let mut buf = MaybeUninit::uninit();
@@ -414,6 +374,10 @@ pub fn fstatat<P: ?Sized + NixPath>(dirfd: Option<RawFd>, path: &P, links: Symli
     .and_then(|ok| ok)
 }
 
+/// Queries the file that `fd` refers to.
+///
+/// On Linux, `fd` may be an `O_PATH` descriptor; for one opened on a symlink
+/// (see `Symlink::Open`), this describes the link itself.
 pub fn fstat(fd: RawFd) -> Result<NodeEntry> {
   cfg_has_statx! {
     use std::ffi::CString;
@@ -429,6 +393,17 @@ pub fn fstat(fd: RawFd) -> Result<NodeEntry> {
   }
   #[allow(clippy::uninit_assumed_init)]
   let mut stat = unsafe { MaybeUninit::<NodeEntryUninit>::uninit().assume_init() };
+  // fstat only accepts O_PATH descriptors since Linux 3.6; AT_EMPTY_PATH works from 2.6.39
+  #[cfg(any(target_os = "linux", target_os = "android"))]
+  let res = unsafe {
+    fstatat64(
+      fd,
+      b"\0".as_ptr() as *const libc::c_char,
+      stat.head.as_mut_ptr(),
+      libc::AT_EMPTY_PATH,
+    )
+  };
+  #[cfg(not(any(target_os = "linux", target_os = "android")))]
   let res = unsafe { fstat64(fd, stat.head.as_mut_ptr()) };
   Errno::result(res)?;
   Ok(NodeEntry::initialize(stat))
@@ -466,5 +441,20 @@ pub fn filetypeat<P: ?Sized + NixPath>(dirfd: RawFd, path: &P, links: Symlink) -
 #[cfg(test)]
 mod tests {
   use super::*;
-  // TODO
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_fstat_opath() {
+    use crate::open::{openat, Mode, OFlag};
+    let tempdir = tempfile::tempdir().unwrap();
+    let _file = std::fs::File::create(tempdir.path().join("file")).unwrap();
+    let path = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &path).unwrap();
+    let fd = openat(None, &path, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    let entry = fstat(fd.as_raw()).unwrap();
+    assert_eq!(entry.st_mode & libc::S_IFMT, libc::S_IFLNK);
+    assert_eq!(entry.st_ino, fstatat(None, &path, Symlink::Open).unwrap().st_ino);
+    let fd = openat(None, &path, OFlag::O_PATH, Mode::empty(), Symlink::Follow).unwrap();
+    assert_eq!(fstat(fd.as_raw()).unwrap().st_mode & libc::S_IFMT, libc::S_IFREG);
+  }
 }
@@ -3,16 +3,17 @@
 
 
 use nix::{errno::Errno, Error, /*NixPath,*/ Result};
-use std::os::unix::io::RawFd;
+
+use crate::fd::OwnedFd;
 
 use std::ffi::{CStr, OsString};
 use std::path::PathBuf;
 
 #[inline]
-// pub fn with_mkstempat<P: ?Sized + NixPath, F>(prefix: &CStr, suffix: Option<&CStr>, f: F) -> Result<(RawFd, PathBuf)>
-pub fn with_mkstempat<F>(prefix: &CStr, suffix: Option<&CStr>, f: F) -> Result<(RawFd, PathBuf)>
+// pub fn with_mkstempat<P: ?Sized + NixPath, F>(prefix: &CStr, suffix: Option<&CStr>, f: F) -> Result<(OwnedFd, PathBuf)>
+pub fn with_mkstempat<F>(prefix: &CStr, suffix: Option<&CStr>, f: F) -> Result<(OwnedFd, PathBuf)>
 where
-  F: Fn(&[u8]) -> Result<RawFd>,
+  F: Fn(&[u8]) -> Result<OwnedFd>,
 {
   let prefix_bytes: &[u8] = prefix.to_bytes();
   let mut path_len = prefix_bytes.len() + 7;
@@ -79,6 +80,6 @@ where
 
 #[cfg(test)]
 mod tests {
-  use super::*;
+  // use super::*;
   // TODO
 }
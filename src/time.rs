@@ -105,17 +105,9 @@ mod inner {
   ) -> Result<()> {
     let fd = openat(dirfd, path, OFlag::O_WRONLY, Mode::empty(), links)?;
     // TODO: may need O_RDWR
-    Errno::result(fd).and_then(|fd| match futime(fd, atime, mtime) {
-      Ok(()) => {
-        let res = unsafe { libc::close(fd) };
-        Errno::result(res).map(drop)
-      }
-      Err(e) => {
-        let res = unsafe { libc::close(fd) };
-        // alternately, we could ignore res and always return Err(e)
-        Errno::result(res).and(Err(e))
-      }
-    })
+    let res = futime(fd.as_raw(), atime, mtime);
+    // alternately, we could ignore close errors and always return res
+    fd.close().and(res)
   }
 }
 
@@ -129,6 +121,9 @@ mod inner {
   ///
   /// [futimens(3p)](http://pubs.opengroup.org/onlinepubs/9699919799/functions/futimens.html).
   /// [futimes(3)](http://man7.org/linux/man-pages/man3/futimes.3.html).
+  ///
+  /// On Linux, `fd` may be an `O_PATH` descriptor, in which case the times are set through
+  /// /proc/self/fd; for a symlink, it's the link itself whose times change.
   pub fn futime<Ta: TimeLike, Tm: TimeLike>(fd: RawFd, atime: &Ta, mtime: &Tm) -> Result<()> {
     let res = if atime.kind() == TimeLikeKind::TimeVal && mtime.kind() == TimeLikeKind::TimeVal {
       let times: [timeval; 2] = [atime.as_timeval(), mtime.as_timeval()];
@@ -137,6 +132,16 @@ mod inner {
       let times: [timespec; 2] = [atime.as_timespec(), mtime.as_timespec()];
       unsafe { libc::futimens(fd, &times[0]) }
     };
+    #[cfg(target_os = "linux")]
+    {
+      use crate::fd::{is_opath, proc_self_fd};
+      if res == -1 && Errno::last() == Errno::EBADF && is_opath(fd) {
+        // the magic link resolves to the inode itself, even for a symlink
+        let times: [timespec; 2] = [atime.as_timespec(), mtime.as_timespec()];
+        let res = unsafe { libc::utimensat(libc::AT_FDCWD, proc_self_fd(fd).as_ptr(), &times[0], 0) };
+        return Errno::result(res).map(drop);
+      }
+    }
     Errno::result(res).map(drop)
   }
 
@@ -167,17 +172,9 @@ mod inner {
       Symlink::Fail => {
         let fd = openat(dirfd, path, OFlag::O_WRONLY, Mode::empty(), links)?;
         // TODO: may need O_RDWR
-        match futime(fd, atime, mtime) {
-          Ok(()) => {
-            let res = unsafe { libc::close(fd) };
-            return Errno::result(res).map(drop);
-          }
-          Err(e) => {
-            let res = unsafe { libc::close(fd) };
-            // alternately, we could ignore res and always return Err(e)
-            return Errno::result(res).and(Err(e));
-          }
-        }
+        let res = futime(fd.as_raw(), atime, mtime);
+        // alternately, we could ignore close errors and always return res
+        return fd.close().and(res);
       }
     };
     let times: [timespec; 2] = [atime.as_timespec(), mtime.as_timespec()];
@@ -340,9 +337,9 @@ impl ClockLike for Duration {
   #[inline]
   fn as_rand_bits(&self) -> u64 {
     #[cfg(any(target_os = "macos", target_os = "ios"))]
-    return ((self.subsec_micros() as u64) * 65537) ^ (self.as_secs() as u64);
+    return ((self.subsec_micros() as u64) * 65537) ^ self.as_secs();
     #[cfg(not(any(target_os = "macos", target_os = "ios")))]
-    return ((self.subsec_nanos() as u64) * 65537) ^ (self.as_secs() as u64);
+    return ((self.subsec_nanos() as u64) * 65537) ^ self.as_secs();
   }
 }
 
@@ -384,5 +381,19 @@ impl ClockLike for TimeVal {
 #[cfg(test)]
 mod tests {
   use super::*;
-  // TODO
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn test_futime_opath() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let _file = std::fs::File::create(tempdir.path().join("file")).unwrap();
+    let path = tempdir.path().join("link");
+    std::os::unix::fs::symlink("file", &path).unwrap();
+    let fd = openat(None, &path, OFlag::empty(), Mode::empty(), Symlink::Open).unwrap();
+    futime(fd.as_raw(), &Omit, &Duration::from_secs(1000)).unwrap();
+    assert_eq!(nix::sys::stat::lstat(&path).unwrap().st_mtime, 1000);
+    assert_ne!(nix::sys::stat::stat(&path).unwrap().st_mtime, 1000);
+    futime(fd.as_raw(), &TimeVal::seconds(2000), &TimeVal::seconds(3000)).unwrap();
+    assert_eq!(nix::sys::stat::lstat(&path).unwrap().st_mtime, 3000);
+  }
 }
@@ -0,0 +1,209 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, NixPath, Result};
+use std::os::unix::io::{AsRawFd, RawFd};
+
+use crate::fd::{proc_self_fd, OwnedFd};
+use crate::open::{openat, Mode, OFlag, Symlink};
+use crate::temp::with_mkstempat;
+use std::ffi::{CStr, CString};
+use std::os::unix::ffi::OsStringExt;
+
+/// A regular file that has no name until it's published.
+///
+/// On Linux this is created with `O_TMPFILE`, so that nothing else can see the file
+/// until `publish` links it into its directory. Where the filesystem doesn't support
+/// that, a file with a `with_mkstempat`-generated name is used instead; `publish` links it
+/// under its real name, and the temporary name is removed when the `AnonFile` goes away.
+#[derive(Debug)]
+pub struct AnonFile {
+  fd: OwnedFd,
+  dir: AnonDir,
+}
+
+// The directory holding an `AnonFile`, and the file's temporary name there if it has one,
+// which is removed on drop.
+#[derive(Debug)]
+struct AnonDir {
+  fd: OwnedFd,
+  temp_name: Option<CString>,
+}
+
+impl Drop for AnonDir {
+  fn drop(&mut self) {
+    if let Some(ref temp_name) = self.temp_name {
+      unsafe { libc::unlinkat(self.fd.as_raw(), temp_name.as_ptr(), 0) };
+    }
+  }
+}
+
+fn link_fd(fd: RawFd, dir: RawFd, name: &CStr) -> Result<()> {
+  #[cfg(target_os = "linux")]
+  {
+    // AT_EMPTY_PATH needs CAP_DAC_READ_SEARCH; otherwise fall back to the /proc magic link
+    let empty = CString::new("").unwrap();
+    let res = unsafe { libc::linkat(fd, empty.as_ptr(), dir, name.as_ptr(), libc::AT_EMPTY_PATH) };
+    match Errno::result(res) {
+      Err(Error::Sys(Errno::ENOENT)) | Err(Error::Sys(Errno::EPERM)) | Err(Error::Sys(Errno::EINVAL)) => (),
+      res => return res.map(drop),
+    }
+    let proc_path = proc_self_fd(fd);
+    let res = unsafe {
+      libc::linkat(
+        libc::AT_FDCWD,
+        proc_path.as_ptr(),
+        dir,
+        name.as_ptr(),
+        libc::AT_SYMLINK_FOLLOW,
+      )
+    };
+    Errno::result(res).map(drop)
+  }
+  #[cfg(not(target_os = "linux"))]
+  {
+    let _ = (fd, dir, name);
+    Err(Error::UnsupportedOperation)
+  }
+}
+
+impl AnonFile {
+  /// Creates an unnamed regular file, open for reading and writing, in the directory `dir`.
+  ///
+  /// If `dirfd` has a value, then `dir` is relative to directory associated with the file descriptor.
+  ///
+  /// If `dirfd` is `None`, then `dir` is relative to the current working directory.
+  ///
+  /// Falls back to a temporarily named file when `O_TMPFILE` fails with EOPNOTSUPP
+  /// (filesystem doesn't support it) or EISDIR (kernel before 3.11).
+  pub fn create_in<P: ?Sized + NixPath>(dirfd: Option<RawFd>, dir: &P, mode: Mode) -> Result<AnonFile> {
+    let dir = openat(
+      dirfd,
+      dir,
+      OFlag::O_RDONLY | OFlag::O_DIRECTORY | OFlag::O_CLOEXEC,
+      Mode::empty(),
+      Symlink::Follow,
+    )?;
+    #[cfg(target_os = "linux")]
+    {
+      let oflags = OFlag::O_TMPFILE | OFlag::O_RDWR | OFlag::O_CLOEXEC;
+      match openat(Some(dir.as_raw()), ".", oflags, mode, Symlink::Follow) {
+        Ok(fd) => {
+          return Ok(AnonFile {
+            fd,
+            dir: AnonDir {
+              fd: dir,
+              temp_name: None,
+            },
+          })
+        }
+        Err(Error::Sys(Errno::EOPNOTSUPP)) | Err(Error::Sys(Errno::EISDIR)) => (),
+        Err(e) => return Err(e),
+      }
+    }
+    Self::create_named(dir, mode)
+  }
+
+  fn create_named(dir: OwnedFd, mode: Mode) -> Result<AnonFile> {
+    let prefix = CString::new(".tmp").unwrap();
+    let (fd, temp_name) = with_mkstempat(&prefix, None, |name| {
+      let oflags = OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_RDWR | OFlag::O_CLOEXEC;
+      openat(Some(dir.as_raw()), name, oflags, mode, Symlink::Fail)
+    })?;
+    let temp_name = CString::new(temp_name.into_os_string().into_vec()).map_err(|_| Error::InvalidPath)?;
+    Ok(AnonFile {
+      fd,
+      dir: AnonDir {
+        fd: dir,
+        temp_name: Some(temp_name),
+      },
+    })
+  }
+
+  /// Reports whether the file really is unnamed, rather than the named fallback.
+  pub fn is_anonymous(&self) -> bool {
+    self.dir.temp_name.is_none()
+  }
+
+  #[inline]
+  pub fn as_raw(&self) -> RawFd {
+    self.fd.as_raw()
+  }
+
+  /// Gives the file the name `name` in its directory, failing with EEXIST
+  /// if that's already taken, and returns the still open descriptor.
+  ///
+  /// On failure the file stays unnamed (or keeps its temporary name) and is discarded.
+  pub fn publish<P: ?Sized + NixPath>(self, name: &P) -> Result<OwnedFd> {
+    let AnonFile { fd, dir } = self;
+    name
+      .with_nix_path(|cstr| match dir.temp_name {
+        None => link_fd(fd.as_raw(), dir.fd.as_raw(), cstr),
+        Some(ref temp_name) => {
+          // dropping `dir` will then remove the temporary name
+          let res = unsafe { libc::linkat(dir.fd.as_raw(), temp_name.as_ptr(), dir.fd.as_raw(), cstr.as_ptr(), 0) };
+          Errno::result(res).map(drop)
+        }
+      })
+      .and_then(|ok| ok)
+      .map(|_| fd)
+  }
+}
+
+impl AsRawFd for AnonFile {
+  #[inline]
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd.as_raw()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Read, Write};
+
+  fn publish_and_check(anon: AnonFile, tempdir: &std::path::Path) {
+    let mut file = std::fs::File::from(anon.fd.try_clone().unwrap());
+    file.write_all(b"contents").unwrap();
+    let fd = anon.publish("published").unwrap();
+    drop(fd);
+    let mut buf = String::new();
+    std::fs::File::open(tempdir.join("published"))
+      .unwrap()
+      .read_to_string(&mut buf)
+      .unwrap();
+    assert_eq!(buf, "contents");
+    let names: Vec<_> = std::fs::read_dir(tempdir)
+      .unwrap()
+      .map(|e| e.unwrap().file_name())
+      .collect();
+    assert_eq!(names, vec!["published"]);
+  }
+
+  #[test]
+  fn test_anon_file() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let anon = AnonFile::create_in(None, tempdir.path(), Mode::from_bits_truncate(0o600)).unwrap();
+    assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 0);
+    publish_and_check(anon, tempdir.path());
+
+    let anon = AnonFile::create_in(None, tempdir.path(), Mode::from_bits_truncate(0o600)).unwrap();
+    assert_eq!(anon.publish("published").err().unwrap().as_errno(), Some(Errno::EEXIST));
+    assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+  }
+
+  #[test]
+  fn test_anon_file_named_fallback() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let dir = openat(None, tempdir.path(), OFlag::O_DIRECTORY, Mode::empty(), Symlink::Follow).unwrap();
+    let anon = AnonFile::create_named(dir, Mode::from_bits_truncate(0o600)).unwrap();
+    assert!(!anon.is_anonymous());
+    assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+    publish_and_check(anon, tempdir.path());
+
+    let dir = openat(None, tempdir.path(), OFlag::O_DIRECTORY, Mode::empty(), Symlink::Follow).unwrap();
+    let anon = AnonFile::create_named(dir, Mode::from_bits_truncate(0o600)).unwrap();
+    drop(anon);
+    assert_eq!(std::fs::read_dir(tempdir.path()).unwrap().count(), 1);
+  }
+}
@@ -0,0 +1,229 @@
+// Copyright 2020 Dubiousjim <dubiousjim@gmail.com>. All rights reserved. MIT license.
+#![allow(dead_code)]
+
+use nix::{errno::Errno, Error, Result};
+
+use crate::bytes::{read_all, strip_newline};
+use crate::chown::{Gid, Uid};
+use crate::idmap::{IdMap, IdRange};
+#[cfg(not(target_env = "musl"))]
+use crate::stat::NodeEntry;
+use nix::unistd::Pid;
+
+/// Whether processes in a user namespace may call setgroups(2), from /proc/<pid>/setgroups.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SetGroups {
+  Allow,
+  Deny,
+}
+
+/// A file's owner or group as seen from inside a user namespace.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Owner<T> {
+  Mapped(T),
+  /// The id has no mapping into the namespace, so the kernel reports the overflow id
+  /// (usually 65534, "nobody") instead.
+  Unmapped,
+}
+
+/// The id mappings of a user namespace, as /proc/<pid>/uid_map, gid_map and setgroups show them.
+///
+/// The inside of each map has the namespace's ids; the outside has the corresponding ids
+/// in the namespace of whoever read the maps, or if that's the same namespace, in its
+/// parent. So for the current process, the outside is the host's view when there's one
+/// level of nesting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UserNamespace {
+  pub uid_map: IdMap,
+  pub gid_map: IdMap,
+  pub setgroups: SetGroups,
+  /// The ids the kernel shows for owners that aren't mapped (/proc/sys/kernel/overflowuid and overflowgid).
+  pub overflow_uid: Uid,
+  pub overflow_gid: Gid,
+}
+
+fn read_proc(path: &str) -> Result<Vec<u8>> {
+  read_all(path).map_err(|e| Error::Sys(e.raw_os_error().map_or(Errno::EIO, Errno::from_i32)))
+}
+
+fn parse_u32(word: &[u8]) -> Result<u32> {
+  std::str::from_utf8(word)
+    .ok()
+    .and_then(|s| s.parse().ok())
+    .ok_or(Error::Sys(Errno::EINVAL))
+}
+
+/// Parses the contents of a uid_map or gid_map file: a line "inside outside count" for
+/// each range. Fails with EINVAL if it's malformed. An empty map (one that hasn't been
+/// written yet) maps nothing.
+pub fn parse_id_map(text: &[u8]) -> Result<IdMap> {
+  let mut ranges = Vec::new();
+  for line in text.split(|b| *b == b'\n') {
+    let words: Vec<&[u8]> = line.split(u8::is_ascii_whitespace).filter(|w| !w.is_empty()).collect();
+    match words[..] {
+      [] => continue,
+      [inside, outside, count] => ranges.push(IdRange {
+        inside: parse_u32(inside)?,
+        outside: parse_u32(outside)?,
+        count: parse_u32(count)?,
+      }),
+      _ => return Err(Error::Sys(Errno::EINVAL)),
+    }
+  }
+  IdMap::new(ranges)
+}
+
+impl UserNamespace {
+  /// Reads the mappings of the user namespace of process `pid`, or of the current process if that's `None`.
+  ///
+  /// A missing setgroups file (before Linux 3.19) counts as `SetGroups::Allow`, and
+  /// unreadable overflow ids as 65534.
+  pub fn read(pid: Option<Pid>) -> Result<Self> {
+    let dir = match pid {
+      Some(pid) => format!("/proc/{}", pid),
+      None => "/proc/self".to_string(),
+    };
+    let setgroups = match read_proc(&format!("{}/setgroups", dir)) {
+      Ok(ref text) if strip_newline(text) == b"deny" => SetGroups::Deny,
+      Ok(ref text) if strip_newline(text) == b"allow" => SetGroups::Allow,
+      Ok(_) => return Err(Error::Sys(Errno::EINVAL)),
+      Err(Error::Sys(Errno::ENOENT)) => SetGroups::Allow,
+      Err(e) => return Err(e),
+    };
+    let overflow = |name| {
+      read_proc(&format!("/proc/sys/kernel/{}", name))
+        .ok()
+        .and_then(|text| parse_u32(strip_newline(&text)).ok())
+        .unwrap_or(65534)
+    };
+    Ok(UserNamespace {
+      uid_map: parse_id_map(&read_proc(&format!("{}/uid_map", dir))?)?,
+      gid_map: parse_id_map(&read_proc(&format!("{}/gid_map", dir))?)?,
+      setgroups,
+      overflow_uid: Uid::from_raw(overflow("overflowuid")),
+      overflow_gid: Gid::from_raw(overflow("overflowgid")),
+    })
+  }
+
+  /// Whether this is the initial user namespace, which maps every id to itself.
+  pub fn is_initial(&self) -> bool {
+    self.uid_map.ranges()
+      == [IdRange {
+        inside: 0,
+        outside: 0,
+        count: u32::MAX,
+      }]
+  }
+
+  /// The outside id for `uid`, or `None` if it isn't mapped.
+  pub fn uid_to_host(&self, uid: Uid) -> Option<Uid> {
+    self.uid_map.to_outside(uid.as_raw()).map(Uid::from_raw)
+  }
+
+  /// The namespace's id for the outside id `uid`, or `None` if it isn't mapped.
+  pub fn uid_from_host(&self, uid: Uid) -> Option<Uid> {
+    self.uid_map.to_inside(uid.as_raw()).map(Uid::from_raw)
+  }
+
+  pub fn gid_to_host(&self, gid: Gid) -> Option<Gid> {
+    self.gid_map.to_outside(gid.as_raw()).map(Gid::from_raw)
+  }
+
+  pub fn gid_from_host(&self, gid: Gid) -> Option<Gid> {
+    self.gid_map.to_inside(gid.as_raw()).map(Gid::from_raw)
+  }
+
+  /// Interprets an owner as stat reports it inside the namespace: the overflow uid means
+  /// the real owner isn't mapped, unless the overflow uid is itself mapped (then there's no telling).
+  pub fn owner(&self, uid: Uid) -> Owner<Uid> {
+    if uid == self.overflow_uid && self.uid_map.to_outside(uid.as_raw()).is_none() {
+      Owner::Unmapped
+    } else {
+      Owner::Mapped(uid)
+    }
+  }
+
+  /// Like `owner`, for a group.
+  pub fn group(&self, gid: Gid) -> Owner<Gid> {
+    if gid == self.overflow_gid && self.gid_map.to_outside(gid.as_raw()).is_none() {
+      Owner::Unmapped
+    } else {
+      Owner::Mapped(gid)
+    }
+  }
+
+  /// The owner and group of the file that `entry` describes; see `owner`.
+  #[cfg(not(target_env = "musl"))]
+  pub fn entry_owner(&self, entry: &NodeEntry) -> (Owner<Uid>, Owner<Gid>) {
+    (
+      self.owner(Uid::from_raw(entry.st_uid)),
+      self.group(Gid::from_raw(entry.st_gid)),
+    )
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_id_map() {
+    let map = parse_id_map(b"         0     100000      65536\n     65536       1000          1\n").unwrap();
+    let ranges = [
+      IdRange {
+        inside: 0,
+        outside: 100_000,
+        count: 65536,
+      },
+      IdRange {
+        inside: 65536,
+        outside: 1000,
+        count: 1,
+      },
+    ];
+    assert_eq!(map.ranges(), ranges);
+    assert_eq!(parse_id_map(b"").unwrap(), IdMap::default());
+    let einval = Some(Errno::EINVAL);
+    assert_eq!(parse_id_map(b"0 0\n").err().unwrap().as_errno(), einval);
+    assert_eq!(parse_id_map(b"0 0 1 1\n").err().unwrap().as_errno(), einval);
+    assert_eq!(parse_id_map(b"0 0 x\n").err().unwrap().as_errno(), einval);
+    assert_eq!(parse_id_map(b"0 0 10\n5 100 1\n").err().unwrap().as_errno(), einval);
+  }
+
+  #[test]
+  fn test_user_namespace() {
+    let ns = UserNamespace {
+      uid_map: parse_id_map(b"0 100000 65536\n").unwrap(),
+      gid_map: parse_id_map(b"0 100000 100\n").unwrap(),
+      setgroups: SetGroups::Deny,
+      overflow_uid: Uid::from_raw(65534),
+      overflow_gid: Gid::from_raw(65534),
+    };
+    assert!(!ns.is_initial());
+    assert_eq!(ns.uid_to_host(Uid::from_raw(5)), Some(Uid::from_raw(100_005)));
+    assert_eq!(ns.uid_from_host(Uid::from_raw(100_005)), Some(Uid::from_raw(5)));
+    assert_eq!(ns.uid_from_host(Uid::from_raw(5)), None);
+    assert_eq!(ns.gid_to_host(Gid::from_raw(100)), None);
+    assert_eq!(ns.gid_from_host(Gid::from_raw(100_099)), Some(Gid::from_raw(99)));
+    // 65534 is mapped for users, so it can't be told from a real owner; not for groups
+    assert_eq!(ns.owner(Uid::from_raw(65534)), Owner::Mapped(Uid::from_raw(65534)));
+    assert_eq!(ns.group(Gid::from_raw(65534)), Owner::Unmapped);
+    assert_eq!(ns.group(Gid::from_raw(5)), Owner::Mapped(Gid::from_raw(5)));
+
+    let ns = UserNamespace::read(None).unwrap();
+    assert_eq!(ns, UserNamespace::read(Some(nix::unistd::getpid())).unwrap());
+    for r in ns.uid_map.ranges() {
+      assert_eq!(ns.uid_to_host(Uid::from_raw(r.inside)), Some(Uid::from_raw(r.outside)));
+    }
+    #[cfg(not(target_env = "musl"))]
+    {
+      use crate::open::Symlink;
+      use crate::stat::fstatat;
+      let entry = fstatat(None, "/", Symlink::Follow).unwrap();
+      let (owner, _) = ns.entry_owner(&entry);
+      if ns.is_initial() {
+        assert_eq!(owner, Owner::Mapped(Uid::from_raw(entry.st_uid)));
+      }
+    }
+  }
+}